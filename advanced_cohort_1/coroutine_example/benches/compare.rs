@@ -0,0 +1,316 @@
+//! Benchmarks: coroutines versus threads versus async tasks
+//! Run with `cargo bench`. Each benchmark does the same work three ways:
+//! with coroutines on a Scheduler, with std::thread and with tasks on a
+//! single-threaded tokio runtime:
+//! - yield: two tasks taking turns; the cost of one switch between them
+//! - ping-pong: two tasks bouncing a message over a pair of channels; the
+//!   cost of one round trip
+//! - spawn + join: starting an empty task and collecting its result
+//! - memory: how much resident memory a parked task adds
+//!
+//! Coroutine and thread stacks both reserve 2MB of address space, but only
+//! the pages a task touches count as resident. A tokio task has no stack:
+//! it is a heap-allocated state machine.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use coroutine_example::{unbounded, yield_now, Scheduler};
+
+/// Switches per task in the yield benchmark
+const YIELDS: u32 = 200_000;
+/// Round trips in the ping-pong benchmark; threads get fewer, they are slow
+const ROUND_TRIPS: u32 = 100_000;
+const THREAD_ROUND_TRIPS: u32 = 10_000;
+/// Tasks started in the spawn benchmark
+const SPAWNS: u32 = 10_000;
+const THREAD_SPAWNS: u32 = 1_000;
+/// Tasks kept alive at once in the memory benchmark; tokio tasks are so
+/// small that a thousand of them fit in what the allocator already holds
+const PARKED: usize = 1_000;
+const PARKED_FUTURES: usize = 100_000;
+
+/// One line of the comparison table; None where a model has no equivalent
+struct Row {
+    name: &'static str,
+    coroutine: String,
+    thread: Option<String>,
+    tokio: String,
+}
+
+fn main() {
+    if cfg!(debug_assertions) {
+        println!("Warning: this is a debug build, run with `cargo bench` for meaningful numbers\n");
+    }
+
+    let rows = [
+        Row {
+            name: "yield to another task",
+            coroutine: per_op(coroutine_yield(), YIELDS * 2),
+            thread: None,
+            tokio: per_op(tokio_yield(), YIELDS * 2),
+        },
+        Row {
+            name: "ping-pong round trip",
+            coroutine: per_op(coroutine_ping_pong(), ROUND_TRIPS),
+            thread: Some(per_op(thread_ping_pong(), THREAD_ROUND_TRIPS)),
+            tokio: per_op(tokio_ping_pong(), ROUND_TRIPS),
+        },
+        Row {
+            name: "spawn + join",
+            coroutine: per_op(coroutine_spawn(), SPAWNS),
+            thread: Some(per_op(thread_spawn(), THREAD_SPAWNS)),
+            tokio: per_op(tokio_spawn(), SPAWNS),
+        },
+        Row {
+            name: "memory per parked task",
+            coroutine: bytes(coroutine_memory()),
+            thread: Some(bytes(thread_memory())),
+            tokio: bytes(tokio_memory()),
+        },
+    ];
+
+    println!("{:<24} {:>12} {:>12} {:>12}", "", "Scheduler", "std::thread", "tokio task");
+    for row in &rows {
+        let thread = row.thread.as_deref().unwrap_or("-");
+        println!("{:<24} {:>12} {:>12} {:>12}", row.name, row.coroutine, thread, row.tokio);
+    }
+}
+
+fn coroutine_yield() -> Duration {
+    let mut scheduler = Scheduler::new();
+    for _ in 0..2 {
+        scheduler.spawn(|| {
+            for _ in 0..YIELDS {
+                yield_now();
+            }
+        });
+    }
+
+    let start = Instant::now();
+    scheduler.run();
+    start.elapsed()
+}
+
+fn tokio_yield() -> Duration {
+    tokio_runtime().block_on(async {
+        let start = Instant::now();
+        let tasks: Vec<_> = (0..2)
+            .map(|_| {
+                tokio::spawn(async {
+                    for _ in 0..YIELDS {
+                        tokio::task::yield_now().await;
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        start.elapsed()
+    })
+}
+
+fn coroutine_ping_pong() -> Duration {
+    let mut scheduler = Scheduler::new();
+    let (ping_tx, ping_rx) = unbounded();
+    let (pong_tx, pong_rx) = unbounded();
+
+    scheduler.spawn(move || {
+        for round in 0..ROUND_TRIPS {
+            ping_tx.send(round).unwrap();
+            pong_rx.recv().unwrap();
+        }
+    });
+    scheduler.spawn(move || {
+        for round in ping_rx {
+            pong_tx.send(round).unwrap();
+        }
+    });
+
+    let start = Instant::now();
+    scheduler.run();
+    start.elapsed()
+}
+
+fn thread_ping_pong() -> Duration {
+    let (ping_tx, ping_rx) = mpsc::channel();
+    let (pong_tx, pong_rx) = mpsc::channel();
+
+    let start = Instant::now();
+    let echo = thread::spawn(move || {
+        for round in ping_rx {
+            pong_tx.send(round).unwrap();
+        }
+    });
+    for round in 0..THREAD_ROUND_TRIPS {
+        ping_tx.send(round).unwrap();
+        pong_rx.recv().unwrap();
+    }
+    drop(ping_tx);
+    echo.join().unwrap();
+    start.elapsed()
+}
+
+fn tokio_ping_pong() -> Duration {
+    tokio_runtime().block_on(async {
+        let (ping_tx, mut ping_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (pong_tx, mut pong_rx) = tokio::sync::mpsc::unbounded_channel();
+
+        let start = Instant::now();
+        let echo = tokio::spawn(async move {
+            while let Some(round) = ping_rx.recv().await {
+                pong_tx.send(round).unwrap();
+            }
+        });
+        for round in 0..ROUND_TRIPS {
+            ping_tx.send(round).unwrap();
+            pong_rx.recv().await.unwrap();
+        }
+        drop(ping_tx);
+        echo.await.unwrap();
+        start.elapsed()
+    })
+}
+
+fn coroutine_spawn() -> Duration {
+    let mut scheduler = Scheduler::new();
+
+    let start = Instant::now();
+    let handles: Vec<_> = (0..SPAWNS).map(|n| scheduler.spawn(move || n)).collect();
+    scheduler.run();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn thread_spawn() -> Duration {
+    let start = Instant::now();
+    let handles: Vec<_> = (0..THREAD_SPAWNS).map(|n| thread::spawn(move || n)).collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn tokio_spawn() -> Duration {
+    tokio_runtime().block_on(async {
+        let start = Instant::now();
+        let handles: Vec<_> = (0..SPAWNS).map(|n| tokio::spawn(async move { n })).collect();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        start.elapsed()
+    })
+}
+
+/// Resident memory added by PARKED coroutines that have each run once
+fn coroutine_memory() -> usize {
+    // A fresh scheduler, so no stack comes from a pool with pages already touched
+    let mut scheduler = Scheduler::new();
+    let before = resident_bytes();
+
+    for _ in 0..PARKED {
+        scheduler.spawn(yield_now);
+    }
+    // Runs once all the others have yielded, while their stacks are live
+    let measured = scheduler.spawn(move || resident_bytes().saturating_sub(before));
+
+    scheduler.run();
+    measured.join().unwrap() / PARKED
+}
+
+fn thread_memory() -> usize {
+    let arrived = Arc::new(Barrier::new(PARKED + 1));
+    let release = Arc::new(Barrier::new(PARKED + 1));
+    let before = resident_bytes();
+
+    let threads: Vec<_> = (0..PARKED)
+        .map(|_| {
+            let (arrived, release) = (arrived.clone(), release.clone());
+            thread::spawn(move || {
+                arrived.wait();
+                release.wait();
+            })
+        })
+        .collect();
+
+    arrived.wait();
+    let grown = resident_bytes().saturating_sub(before);
+    release.wait();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    grown / PARKED
+}
+
+fn tokio_memory() -> usize {
+    tokio_runtime().block_on(async {
+        // Permits, unlike Notify::notify_waiters, can't be missed by a task
+        // that gets to its await late
+        let release = Arc::new(tokio::sync::Semaphore::new(0));
+        let started = Arc::new(AtomicUsize::new(0));
+        let before = resident_bytes();
+
+        let tasks: Vec<_> = (0..PARKED_FUTURES)
+            .map(|_| {
+                let (release, started) = (release.clone(), started.clone());
+                tokio::spawn(async move {
+                    started.fetch_add(1, Ordering::Relaxed);
+                    let _permit = release.acquire().await.unwrap();
+                })
+            })
+            .collect();
+        // Let every task run up to its await
+        while started.load(Ordering::Relaxed) < PARKED_FUTURES {
+            tokio::task::yield_now().await;
+        }
+
+        let grown = resident_bytes().saturating_sub(before);
+        release.add_permits(PARKED_FUTURES);
+        for task in tasks {
+            task.await.unwrap();
+        }
+        grown / PARKED_FUTURES
+    })
+}
+
+fn tokio_runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .expect("failed to start a tokio runtime")
+}
+
+/// Resident set size of this process, from /proc/self/statm
+fn resident_bytes() -> usize {
+    let statm = std::fs::read_to_string("/proc/self/statm").expect("failed to read /proc/self/statm");
+    let pages: usize = statm
+        .split_whitespace()
+        .nth(1)
+        .and_then(|pages| pages.parse().ok())
+        .expect("unexpected /proc/self/statm format");
+    pages * unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Formats the average duration of one of `count` operations
+fn per_op(total: Duration, count: u32) -> String {
+    let nanos = total.as_nanos() as f64 / f64::from(count);
+    if nanos < 1_000.0 {
+        format!("{:.0} ns", nanos)
+    } else if nanos < 1_000_000.0 {
+        format!("{:.1} µs", nanos / 1_000.0)
+    } else {
+        format!("{:.1} ms", nanos / 1_000_000.0)
+    }
+}
+
+fn bytes(count: usize) -> String {
+    if count < 1024 {
+        format!("{} B", count)
+    } else {
+        format!("{:.1} KB", count as f64 / 1024.0)
+    }
+}
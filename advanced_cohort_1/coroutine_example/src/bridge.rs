@@ -0,0 +1,182 @@
+//! Async/await bridge
+//! Stackful coroutines and stackless futures solve the same problem in
+//! different ways: a coroutine keeps its whole call stack while suspended,
+//! a future compiles its suspension points into a state machine that
+//! returns Poll::Pending. This module lets both run on the same Scheduler:
+//! 1. spawn_future() wraps a future in a task that sits in the same ready
+//!    queue as coroutines, but has no stack: "resuming" it means polling it
+//!    once on the scheduler's own stack
+//! 2. Its Waker is the same kind coroutines get, so waking it requeues the
+//!    task onto the ready queue
+//! 3. JoinHandle is a Future too, so async code can .await coroutines and
+//!    coroutines can join() futures
+//!
+//! Futures must not call yield_now(), park(), sleep() or blocking channel
+//! operations: there is no stack to suspend. Awaiting is the way to wait.
+
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::{pin, Pin};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll, Waker};
+
+use super::{
+    policy, report_failure, AnyCoroutine, CancelFlag, CoroutineState, CoroutineWaker, JoinError,
+    JoinHandle, JoinSlot, JoinState, Scheduler, Stack, Task, NEXT_COROUTINE_ID,
+};
+
+/// Id reserved for the future passed to block_on()
+/// Coroutine ids start at 1, so this one never names a queued task.
+const BLOCK_ON_ID: usize = 0;
+
+/// A future living in the scheduler's ready queue
+struct FutureTask {
+    id: usize,
+    future: Option<Pin<Box<dyn Future<Output = ()>>>>,  // Dropped on cancellation
+    state: CoroutineState,
+    join: Option<Arc<dyn JoinSlot>>,
+    waker: Option<Waker>,
+    cancel: Arc<CancelFlag>,
+}
+
+impl AnyCoroutine for FutureTask {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn waker(&self) -> Option<Waker> {
+        self.waker.clone()
+    }
+
+    fn state(&self) -> &CoroutineState {
+        &self.state
+    }
+
+    fn set_state(&mut self, state: CoroutineState) {
+        self.state = state;
+    }
+
+    fn cancel_requested(&self) -> bool {
+        self.cancel.is_requested()
+    }
+
+    /// Polls the future once
+    /// Pending becomes Blocked: the task waits for its Waker like a parked
+    /// coroutine would. A cancelled future is dropped instead, which runs
+    /// Drop for everything it holds across its await points.
+    unsafe fn resume(&mut self) {
+        if self.cancel_requested() {
+            self.future = None;
+            self.state = CoroutineState::Cancelled;
+            return;
+        }
+
+        let waker = self.waker.clone().expect("future task without a waker");
+        let mut cx = TaskContext::from_waker(&waker);
+        let future = self.future.as_mut().expect("future task resumed after it finished").as_mut();
+
+        self.state = match panic::catch_unwind(AssertUnwindSafe(|| future.poll(&mut cx))) {
+            Ok(Poll::Ready(())) => CoroutineState::Complete,
+            Ok(Poll::Pending) => CoroutineState::Blocked,
+            Err(payload) => CoroutineState::Panicked(payload),
+        };
+    }
+
+    unsafe fn suspend(&mut self, _state: CoroutineState) {
+        // Unwinds out of poll() and ends up as this task's JoinError
+        panic!("a future can't suspend like a coroutine; .await instead");
+    }
+
+    fn finish(self: Box<Self>) -> Option<Stack> {
+        report_failure(self.state, self.join);
+        None
+    }
+}
+
+impl Scheduler {
+    /// Adds a future to the scheduler, next to the coroutines
+    /// It gets no stack of its own; the returned handle gives access to
+    /// its output, either through join() or by awaiting it.
+    pub fn spawn_future<F>(&mut self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let state = Arc::new(Mutex::new(JoinState { result: None, waiter: None }));
+        let slot = state.clone();
+        let body = async move {
+            let value = future.await;
+            slot.lock().unwrap().complete(Ok(value));
+        };
+
+        let id = NEXT_COROUTINE_ID.fetch_add(1, Ordering::Relaxed);
+        let waker = Waker::from(Arc::new(CoroutineWaker {
+            id,
+            queue: self.wakeups.clone(),
+        }));
+        let cancel = Arc::new(CancelFlag::new(waker.clone()));
+        let task = FutureTask {
+            id,
+            future: Some(Box::pin(body)),
+            state: CoroutineState::Ready,
+            join: Some(state.clone()),
+            waker: Some(waker),
+            cancel: cancel.clone(),
+        };
+        self.enqueue(Task::new(Box::new(task), 0, None, policy::DEFAULT_WEIGHT));
+
+        JoinHandle { id, state, cancel }
+    }
+
+    /// Runs `future` to completion on the calling thread and returns its output
+    /// Spawned coroutines and futures keep running while it waits, so it
+    /// may await their handles. Unlike spawned work, the future may borrow
+    /// from the caller.
+    pub fn block_on<F: Future>(&mut self, future: F) -> F::Output {
+        let entered = self.enter();
+
+        let waker = Waker::from(Arc::new(CoroutineWaker {
+            id: BLOCK_ON_ID,
+            queue: self.wakeups.clone(),
+        }));
+        let mut cx = TaskContext::from_waker(&waker);
+        let mut future = pin!(future);
+
+        let output = loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                break output;
+            }
+
+            // Run everything else until our waker fires; the wakeup lands in
+            // woken_early since nothing with our id is ever parked
+            loop {
+                let ran = self.run_once();
+                if self.woken_early.remove(&BLOCK_ON_ID) {
+                    break;
+                }
+                if !ran {
+                    self.wait_for_events(self.next_deadline());
+                }
+            }
+        };
+
+        self.leave(entered);
+        output
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock().unwrap();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waiter = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
@@ -0,0 +1,355 @@
+//! Coroutine-aware channels
+//! A std::sync::Mutex or mpsc channel blocks the whole OS thread, which on a
+//! single-threaded scheduler means blocking every coroutine, including the
+//! one that would have unblocked us. These channels block only the calling
+//! coroutine instead:
+//! 1. If the operation can't proceed, the coroutine leaves its Waker in the
+//!    channel and parks
+//! 2. The other side wakes it once there is a value (or room for one)
+//! 3. The scheduler moves it back to the ready queue and it tries again
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::task::Waker;
+
+use super::{current_waker, park};
+
+/// Creates a channel that can hold any number of queued values
+/// Sending never blocks; receiving blocks while the channel is empty.
+pub fn unbounded<T>() -> (Sender<T>, Receiver<T>) {
+    new_channel(None)
+}
+
+/// Creates a channel holding at most `capacity` queued values
+/// Sending blocks while the channel is full, which gives back-pressure.
+///
+/// Panics if `capacity` is 0.
+pub fn bounded<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "bounded channel needs a capacity of at least 1");
+    new_channel(Some(capacity))
+}
+
+fn new_channel<T>(capacity: Option<usize>) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Mutex::new(Shared {
+        queue: VecDeque::new(),
+        capacity,
+        senders: 1,
+        receiver_alive: true,
+        waiting_receivers: VecDeque::new(),
+        waiting_senders: VecDeque::new(),
+    }));
+
+    (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+/// State shared by both halves
+struct Shared<T> {
+    queue: VecDeque<T>,
+    capacity: Option<usize>,             // None means unbounded
+    senders: usize,                      // Live Sender handles
+    receiver_alive: bool,
+    waiting_receivers: VecDeque<Waker>,  // Parked in recv() on an empty queue
+    waiting_senders: VecDeque<Waker>,    // Parked in send() on a full queue
+}
+
+impl<T> Shared<T> {
+    fn is_full(&self) -> bool {
+        self.capacity.is_some_and(|capacity| self.queue.len() >= capacity)
+    }
+}
+
+/// Wakes the first waiter in line, if there is one
+fn wake_one(waiters: &mut VecDeque<Waker>) {
+    if let Some(waker) = waiters.pop_front() {
+        waker.wake();
+    }
+}
+
+/// Wakes everyone; used when one side goes away for good
+fn wake_all(waiters: &mut VecDeque<Waker>) {
+    for waker in waiters.drain(..) {
+        waker.wake();
+    }
+}
+
+/// Parks the calling coroutine, which already queued `waker` in `line`
+/// If the coroutine is cancelled meanwhile, it leaves the line, so the
+/// next wakeup goes to someone still waiting. If its Waker was already
+/// taken out, a wakeup meant for it is on its way; that one is passed on
+/// to the next waiter in line, who would otherwise sleep through it.
+fn wait_in_line<T>(shared: &Mutex<Shared<T>>, waker: Waker, line: fn(&mut Shared<T>) -> &mut VecDeque<Waker>) {
+    struct PassOn<'a, T> {
+        shared: &'a Mutex<Shared<T>>,
+        waker: Waker,
+        line: fn(&mut Shared<T>) -> &mut VecDeque<Waker>,
+    }
+
+    impl<T> Drop for PassOn<'_, T> {
+        fn drop(&mut self) {
+            if thread::panicking() {
+                let mut shared = self.shared.lock().unwrap();
+                let line = (self.line)(&mut shared);
+                let queued = line.len();
+                line.retain(|waiting| !waiting.will_wake(&self.waker));
+                if line.len() == queued {
+                    wake_one(line);
+                }
+            }
+        }
+    }
+
+    let _pass_on = PassOn { shared, waker, line };
+    park();
+}
+
+/// Sending half; clone it to get more producers
+pub struct Sender<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+/// Receiving half
+pub struct Receiver<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+/// The receiver is gone; carries back the value that could not be sent
+#[derive(Debug, PartialEq)]
+pub struct SendError<T>(pub T);
+
+/// Every sender is gone and nothing is left in the queue
+#[derive(Debug, PartialEq)]
+pub struct RecvError;
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sending on a channel whose receiver is gone")
+    }
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "receiving on an empty channel whose senders are gone")
+    }
+}
+
+impl<T> Sender<T> {
+    /// Queues `value`, blocking the current coroutine while the channel is full
+    /// Only call this from inside a coroutine when the channel is bounded.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        loop {
+            let mut shared = self.shared.lock().unwrap();
+
+            if !shared.receiver_alive {
+                return Err(SendError(value));
+            }
+
+            if !shared.is_full() {
+                shared.queue.push_back(value);
+                wake_one(&mut shared.waiting_receivers);
+                return Ok(());
+            }
+
+            // No room: wait for the receiver to make some
+            let waker = current_waker();
+            shared.waiting_senders.push_back(waker.clone());
+            drop(shared);
+            wait_in_line(&self.shared, waker, |shared| &mut shared.waiting_senders);
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().unwrap().senders += 1;
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.senders -= 1;
+
+        // Blocked receivers must get the chance to see the channel is closed
+        if shared.senders == 0 {
+            wake_all(&mut shared.waiting_receivers);
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Takes the next value, blocking the current coroutine while the
+    /// channel is empty
+    /// Returns RecvError once the channel is empty and all senders are gone.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        loop {
+            let mut shared = self.shared.lock().unwrap();
+
+            if let Some(value) = shared.queue.pop_front() {
+                wake_one(&mut shared.waiting_senders);
+                return Ok(value);
+            }
+
+            if shared.senders == 0 {
+                return Err(RecvError);
+            }
+
+            // Nothing yet: wait for a sender to deliver
+            let waker = current_waker();
+            shared.waiting_receivers.push_back(waker.clone());
+            drop(shared);
+            wait_in_line(&self.shared, waker, |shared| &mut shared.waiting_receivers);
+        }
+    }
+
+    /// Takes the next value if one is queued, without ever blocking
+    /// Usable outside of coroutines too.
+    pub fn try_recv(&self) -> Option<T> {
+        let mut shared = self.shared.lock().unwrap();
+        let value = shared.queue.pop_front();
+        if value.is_some() {
+            wake_one(&mut shared.waiting_senders);
+        }
+        value
+    }
+}
+
+impl<T> Iterator for Receiver<T> {
+    type Item = T;
+
+    /// Receives until the channel is closed
+    fn next(&mut self) -> Option<T> {
+        self.recv().ok()
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.receiver_alive = false;
+
+        // Blocked senders would otherwise wait for room forever
+        wake_all(&mut shared.waiting_senders);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{yield_now, JoinError, Scheduler};
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn a_full_bounded_channel_blocks_the_sender() {
+        let mut scheduler = Scheduler::new();
+        let (tx, rx) = bounded(1);
+        let sent = Rc::new(Cell::new(0));
+
+        let counter = sent.clone();
+        scheduler.spawn(move || {
+            for item in 0..3 {
+                tx.send(item).unwrap();
+                counter.set(counter.get() + 1);
+            }
+        });
+        let receiver = scheduler.spawn(move || {
+            // However often the sender gets to run, the second send waits
+            for _ in 0..5 {
+                yield_now();
+            }
+            let sent_before_receiving = sent.get();
+            (sent_before_receiving, rx.collect::<Vec<_>>())
+        });
+
+        scheduler.run();
+        assert_eq!(receiver.join().unwrap(), (1, vec![0, 1, 2]));
+    }
+
+    #[test]
+    fn dropping_every_sender_disconnects_the_receiver() {
+        let mut scheduler = Scheduler::new();
+        let (tx, rx) = unbounded();
+
+        let receiver = scheduler.spawn(move || (rx.recv(), rx.recv()));
+        scheduler.spawn(move || {
+            tx.send(7).unwrap();
+            // A clone keeps the channel open until it goes too
+            let clone = tx.clone();
+            drop(tx);
+            yield_now();
+            drop(clone);
+        });
+
+        scheduler.run();
+        assert_eq!(receiver.join().unwrap(), (Ok(7), Err(RecvError)));
+    }
+
+    #[test]
+    fn dropping_the_receiver_disconnects_a_blocked_sender() {
+        let mut scheduler = Scheduler::new();
+        let (tx, rx) = bounded(1);
+
+        let sender = scheduler.spawn(move || (tx.send(1), tx.send(2)));
+        scheduler.spawn(move || {
+            yield_now();
+            drop(rx);
+        });
+
+        scheduler.run();
+        assert_eq!(sender.join().unwrap(), (Ok(()), Err(SendError(2))));
+    }
+
+    #[test]
+    fn a_wakeup_is_passed_on_past_a_cancelled_receiver() {
+        let mut scheduler = Scheduler::new();
+        let (tx, rx) = unbounded();
+        let rx = Rc::new(rx);
+
+        // Both wait in line, first before second
+        let receiver = rx.clone();
+        let first = scheduler.spawn(move || receiver.recv());
+        let receiver = rx.clone();
+        let second = scheduler.spawn(move || receiver.recv());
+
+        // The value's wakeup goes to `first`, which is cancelled before it
+        // gets to run; `tx` stays open, so nothing else would wake `second`
+        let sender = tx.clone();
+        scheduler.spawn(move || {
+            sender.send(7).unwrap();
+            first.cancel();
+            assert!(matches!(first.join(), Err(JoinError::Cancelled)));
+        });
+
+        scheduler.run();
+        assert_eq!(second.join().unwrap(), Ok(7));
+        drop(tx);
+    }
+
+    #[test]
+    fn a_cancelled_receiver_leaves_the_line() {
+        let mut scheduler = Scheduler::new();
+        let (tx, rx) = unbounded();
+        let rx = Rc::new(rx);
+
+        let receiver = rx.clone();
+        let front = scheduler.spawn(move || receiver.recv());
+        let receiver = rx.clone();
+        let behind = scheduler.spawn(move || receiver.recv());
+
+        // `behind` is cancelled while still in line, before there is any
+        // value; the one value sent afterwards must reach `front`
+        let sender = tx.clone();
+        scheduler.spawn(move || {
+            behind.cancel();
+            assert!(matches!(behind.join(), Err(JoinError::Cancelled)));
+            sender.send(7).unwrap();
+        });
+
+        scheduler.run();
+        assert_eq!(front.join().unwrap(), Ok(7));
+        drop(tx);
+    }
+}
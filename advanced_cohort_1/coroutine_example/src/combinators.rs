@@ -0,0 +1,228 @@
+//! Generator combinators
+//! Iterator adapters only see the yielded values. Generators also take an
+//! input on every resume() and end with a return value, so they get
+//! adapters of their own that keep both:
+//! - chain_with: runs one generator to completion, then the next
+//! - zip_with: resumes two generators in lockstep
+//! - pipe_into: feeds everything one generator yields into another one's
+//!   resume input
+//! - map_yield: transforms the yielded values
+//! - yields / into_stream: view an input-less generator as an Iterator or
+//!   as a futures Stream
+//!
+//! They are named apart from Iterator::chain, Iterator::zip and friends so
+//! that calls on a Generator, which is an Iterator too, stay unambiguous.
+//! Every adapter is itself a GeneratorFunc and can be combined further.
+
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+
+use futures_core::Stream;
+
+use super::{GeneratorFunc, GeneratorState};
+
+/// Runs `first` to completion, then `second`
+/// Each resume() input goes to whichever of the two is running; the one
+/// that completes `first` is handed to `second` as its first input, hence
+/// the Clone. Returns both return values.
+pub struct ChainWith<A, B, RetA> {
+    first: A,
+    second: B,
+    first_return: Option<RetA>,  // Set once `first` has completed
+}
+
+impl<A, B, RetA> ChainWith<A, B, RetA> {
+    pub(crate) fn new(first: A, second: B) -> Self {
+        ChainWith { first, second, first_return: None }
+    }
+}
+
+impl<R, A, B> GeneratorFunc<R> for ChainWith<A, B, A::Return>
+where
+    R: Clone,
+    A: GeneratorFunc<R>,
+    B: GeneratorFunc<R, Yield = A::Yield>,
+{
+    type Yield = A::Yield;
+    type Return = (A::Return, B::Return);
+
+    fn resume(&mut self, input: R) -> GeneratorState<Self::Yield, Self::Return> {
+        if self.first_return.is_none() {
+            match self.first.resume(input.clone()) {
+                GeneratorState::Yielded(value) => return GeneratorState::Yielded(value),
+                GeneratorState::Complete(value) => self.first_return = Some(value),
+            }
+        }
+
+        match self.second.resume(input) {
+            GeneratorState::Yielded(value) => GeneratorState::Yielded(value),
+            GeneratorState::Complete(value) => {
+                let first = self.first_return.take().expect("chained generator resumed after completion");
+                GeneratorState::Complete((first, value))
+            }
+        }
+    }
+}
+
+/// Resumes two generators with the same input and yields their values
+/// in pairs
+/// Completes as soon as either one does, returning what both of them
+/// produced on that last step, so no value is silently dropped.
+pub struct ZipWith<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A, B> ZipWith<A, B> {
+    pub(crate) fn new(first: A, second: B) -> Self {
+        ZipWith { first, second }
+    }
+}
+
+impl<R, A, B> GeneratorFunc<R> for ZipWith<A, B>
+where
+    R: Clone,
+    A: GeneratorFunc<R>,
+    B: GeneratorFunc<R>,
+{
+    type Yield = (A::Yield, B::Yield);
+    type Return = (GeneratorState<A::Yield, A::Return>, GeneratorState<B::Yield, B::Return>);
+
+    fn resume(&mut self, input: R) -> GeneratorState<Self::Yield, Self::Return> {
+        match (self.first.resume(input.clone()), self.second.resume(input)) {
+            (GeneratorState::Yielded(first), GeneratorState::Yielded(second)) => {
+                GeneratorState::Yielded((first, second))
+            }
+            last => GeneratorState::Complete(last),
+        }
+    }
+}
+
+/// Feeds every value `producer` yields into `consumer`
+/// Each resume() runs the producer one step and passes the result to the
+/// consumer as Some(value); once the producer has completed, the consumer
+/// gets None instead until it completes too. The pipe yields whatever the
+/// consumer yields and returns the producer's return value (None if the
+/// consumer finished first) along with the consumer's.
+pub struct PipeInto<P, C, RetP> {
+    producer: P,
+    consumer: C,
+    producer_done: bool,
+    producer_return: Option<RetP>,
+}
+
+impl<P, C, RetP> PipeInto<P, C, RetP> {
+    pub(crate) fn new(producer: P, consumer: C) -> Self {
+        PipeInto { producer, consumer, producer_done: false, producer_return: None }
+    }
+}
+
+impl<R, P, C> GeneratorFunc<R> for PipeInto<P, C, P::Return>
+where
+    P: GeneratorFunc<R>,
+    C: GeneratorFunc<Option<P::Yield>>,
+{
+    type Yield = C::Yield;
+    type Return = (Option<P::Return>, C::Return);
+
+    fn resume(&mut self, input: R) -> GeneratorState<Self::Yield, Self::Return> {
+        let item = if self.producer_done {
+            None
+        } else {
+            match self.producer.resume(input) {
+                GeneratorState::Yielded(value) => Some(value),
+                GeneratorState::Complete(value) => {
+                    self.producer_done = true;
+                    self.producer_return = Some(value);
+                    None
+                }
+            }
+        };
+
+        match self.consumer.resume(item) {
+            GeneratorState::Yielded(value) => GeneratorState::Yielded(value),
+            GeneratorState::Complete(value) => GeneratorState::Complete((self.producer_return.take(), value)),
+        }
+    }
+}
+
+/// Transforms every yielded value with `f`
+pub struct MapYield<G, F> {
+    generator: G,
+    f: F,
+}
+
+impl<G, F> MapYield<G, F> {
+    pub(crate) fn new(generator: G, f: F) -> Self {
+        MapYield { generator, f }
+    }
+}
+
+impl<R, G, F, U> GeneratorFunc<R> for MapYield<G, F>
+where
+    G: GeneratorFunc<R>,
+    F: FnMut(G::Yield) -> U,
+{
+    type Yield = U;
+    type Return = G::Return;
+
+    fn resume(&mut self, input: R) -> GeneratorState<U, G::Return> {
+        match self.generator.resume(input) {
+            GeneratorState::Yielded(value) => GeneratorState::Yielded((self.f)(value)),
+            GeneratorState::Complete(value) => GeneratorState::Complete(value),
+        }
+    }
+}
+
+/// Iterator over the values of a generator that takes no input
+/// The return value is dropped; call resume() directly to get it.
+pub struct Yields<G> {
+    generator: G,
+    done: bool,  // Resuming a completed generator panics
+}
+
+impl<G> Yields<G> {
+    pub(crate) fn new(generator: G) -> Self {
+        Yields { generator, done: false }
+    }
+}
+
+impl<G: GeneratorFunc<()>> Iterator for Yields<G> {
+    type Item = G::Yield;
+
+    fn next(&mut self) -> Option<G::Yield> {
+        if self.done {
+            return None;
+        }
+
+        match self.generator.resume(()) {
+            GeneratorState::Yielded(value) => Some(value),
+            GeneratorState::Complete(_) => {
+                self.done = true;
+                None
+            }
+        }
+    }
+}
+
+/// A generator as a futures Stream
+/// A generator never waits for anything, so every poll runs it to its
+/// next yield and is immediately ready. It still runs on the polling
+/// thread, in between the awaits of whatever consumes the stream.
+pub struct IntoStream<G> {
+    values: Yields<G>,
+}
+
+impl<G> IntoStream<G> {
+    pub(crate) fn new(generator: G) -> Self {
+        IntoStream { values: Yields::new(generator) }
+    }
+}
+
+impl<G: GeneratorFunc<()> + Unpin> Stream for IntoStream<G> {
+    type Item = G::Yield;
+
+    fn poll_next(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<Option<G::Yield>> {
+        Poll::Ready(self.get_mut().values.next())
+    }
+}
@@ -0,0 +1,421 @@
+//! CPU contexts and the context switch
+//! A suspended coroutine is nothing but a stack plus the registers the
+//! calling convention says a function call must preserve. Which registers
+//! those are, and how control gets onto a fresh stack, depends on the ISA,
+//! so each supported one gets its own Context layout and assembly:
+//! - x86_64: rsp, rbx, rbp, r12–r15
+//! - aarch64: sp, x19–x28, the frame pointer x29, the link register x30
+//!   and the low halves d8–d15 of the vector registers
+//! - riscv64: sp, ra, s0–s11 and fs0–fs11
+//!
+//! Everything above this module only uses Context::new(), start(),
+//! set_argument() and coroutine_switch_context(), so the scheduler,
+//! the generators and the demos are the same on every ISA.
+//!
+//! To run the tests on another ISA from an x86_64 Linux host, install a
+//! cross linker and qemu-user and let Cargo use them (see
+//! .cargo/config.toml), e.g.
+//!
+//! ```text
+//! rustup target add aarch64-unknown-linux-gnu
+//! apt install gcc-aarch64-linux-gnu qemu-user
+//! cargo test --target aarch64-unknown-linux-gnu
+//! ```
+
+use std::arch::global_asm;
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "riscv64")))]
+compile_error!("coroutine contexts are only implemented for x86_64, aarch64 and riscv64");
+
+extern "C" {
+    /// Saves the running registers into `save` and resumes from `load`
+    pub fn coroutine_switch_context(save: *mut Context, load: *const Context);
+    /// First instruction executed on a brand new coroutine stack
+    fn coroutine_trampoline();
+}
+
+/// CPU context that needs to be saved/restored during context switches
+/// This is the x86_64 System V layout
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+pub struct Context {
+    // Register state
+    rsp: u64,    // Stack pointer - crucial for resuming execution
+    r15: u64,    // Callee-saved registers that must be preserved
+    r14: u64,    // across function calls according to ABI
+    r13: u64,
+    r12: u64,
+    rbx: u64,
+    rbp: u64,    // Frame pointer
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Context {
+    pub fn new() -> Self {
+        Context {
+            rsp: 0, r15: 0, r14: 0, r13: 0, r12: 0, rbx: 0, rbp: 0
+        }
+    }
+
+    /// Builds the frame the first context switch onto a new stack returns into
+    /// Teaching points:
+    /// - Stack growth direction
+    /// - Alignment requirements
+    /// - Initial stack frame setup
+    ///
+    /// # Safety
+    /// `stack_top` must be the end of writable memory that stays alive, and
+    /// `entry` an `extern "C" fn(usize) -> !`.
+    pub unsafe fn start(&mut self, stack_top: usize, entry: usize) {
+        // Ensure proper stack alignment (16 bytes for x86_64); the stack
+        // grows downward from the top
+        let sp = stack_top & !15;
+
+        // Plant the trampoline as the return address of the first switch.
+        // Once `ret` pops it, rsp is back at the 16-byte aligned top, which
+        // is exactly what the ABI expects right before the trampoline's `call`.
+        let sp = sp - 8;
+        std::ptr::write(sp as *mut u64, coroutine_trampoline as *const () as u64);
+
+        self.rsp = sp as u64;
+        self.r13 = entry as u64;
+    }
+
+    /// What the entry function receives when the context first runs
+    pub fn set_argument(&mut self, argument: usize) {
+        self.r12 = argument as u64;
+    }
+}
+
+// The actual context switch, written in assembly because it has to touch
+// the stack pointer directly, which no Rust function can do safely.
+//
+// switch_context(save: *mut Context, load: *const Context)
+// - rdi = where to store the current registers
+// - rsi = where to load the next registers from
+//
+// Only callee-saved registers are stored: everything else has already been
+// spilled by the compiler because, from its point of view, this is just an
+// ordinary function call. The return address sits on top of the stack, so
+// after swapping rsp the final `ret` "returns" into the other coroutine.
+//
+// coroutine_trampoline is the first code a fresh coroutine runs. The
+// initial Context parks the coroutine pointer in r12 and the entry function
+// in r13, so the trampoline only has to move them into place and call.
+#[cfg(target_arch = "x86_64")]
+global_asm!(
+    ".pushsection .text",
+    ".global coroutine_switch_context",
+    "coroutine_switch_context:",
+    "    mov [rdi + 0x00], rsp",
+    "    mov [rdi + 0x08], r15",
+    "    mov [rdi + 0x10], r14",
+    "    mov [rdi + 0x18], r13",
+    "    mov [rdi + 0x20], r12",
+    "    mov [rdi + 0x28], rbx",
+    "    mov [rdi + 0x30], rbp",
+    "    mov rsp, [rsi + 0x00]",
+    "    mov r15, [rsi + 0x08]",
+    "    mov r14, [rsi + 0x10]",
+    "    mov r13, [rsi + 0x18]",
+    "    mov r12, [rsi + 0x20]",
+    "    mov rbx, [rsi + 0x28]",
+    "    mov rbp, [rsi + 0x30]",
+    "    ret",
+    "",
+    ".global coroutine_trampoline",
+    "coroutine_trampoline:",
+    "    mov rdi, r12",
+    "    call r13",
+    "    ud2",
+    ".popsection",
+);
+
+/// CPU context that needs to be saved/restored during context switches
+/// This is the AAPCS64 layout. Only the low 64 bits of v8–v15 are
+/// callee-saved, which is exactly d8–d15.
+#[cfg(target_arch = "aarch64")]
+#[repr(C)]
+#[derive(Default)]
+pub struct Context {
+    sp: u64,       // Stack pointer
+    x19: u64,      // Callee-saved general purpose registers
+    x20: u64,
+    x21: u64,
+    x22: u64,
+    x23: u64,
+    x24: u64,
+    x25: u64,
+    x26: u64,
+    x27: u64,
+    x28: u64,
+    x29: u64,      // Frame pointer
+    x30: u64,      // Link register: where `ret` jumps to
+    d: [u64; 8],   // Callee-saved floating point registers d8–d15
+}
+
+#[cfg(target_arch = "aarch64")]
+impl Context {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Prepares the first context switch onto a new stack
+    /// Teaching points:
+    /// - `ret` jumps to the link register instead of popping the stack,
+    ///   so nothing has to be written to the new stack
+    /// - sp must be 16-byte aligned at all times
+    ///
+    /// # Safety
+    /// `stack_top` must be the end of writable memory that stays alive, and
+    /// `entry` an `extern "C" fn(usize) -> !`.
+    pub unsafe fn start(&mut self, stack_top: usize, entry: usize) {
+        self.sp = (stack_top & !15) as u64;
+        self.x30 = coroutine_trampoline as *const () as u64;
+        self.x20 = entry as u64;
+    }
+
+    /// What the entry function receives when the context first runs
+    pub fn set_argument(&mut self, argument: usize) {
+        self.x19 = argument as u64;
+    }
+}
+
+// Same idea as on x86_64, with x0 = save and x1 = load. sp can't be stored
+// or loaded directly, so it goes through the scratch register x9. The
+// final `ret` jumps to the x30 just loaded: right after the call to
+// coroutine_switch_context in the other coroutine, or the trampoline.
+//
+// The trampoline finds the coroutine pointer in x19 and the entry
+// function in x20.
+#[cfg(target_arch = "aarch64")]
+global_asm!(
+    ".pushsection .text",
+    ".global coroutine_switch_context",
+    ".p2align 2",
+    "coroutine_switch_context:",
+    "    mov x9, sp",
+    "    str x9, [x0, #0x00]",
+    "    stp x19, x20, [x0, #0x08]",
+    "    stp x21, x22, [x0, #0x18]",
+    "    stp x23, x24, [x0, #0x28]",
+    "    stp x25, x26, [x0, #0x38]",
+    "    stp x27, x28, [x0, #0x48]",
+    "    stp x29, x30, [x0, #0x58]",
+    "    stp d8, d9, [x0, #0x68]",
+    "    stp d10, d11, [x0, #0x78]",
+    "    stp d12, d13, [x0, #0x88]",
+    "    stp d14, d15, [x0, #0x98]",
+    "    ldr x9, [x1, #0x00]",
+    "    mov sp, x9",
+    "    ldp x19, x20, [x1, #0x08]",
+    "    ldp x21, x22, [x1, #0x18]",
+    "    ldp x23, x24, [x1, #0x28]",
+    "    ldp x25, x26, [x1, #0x38]",
+    "    ldp x27, x28, [x1, #0x48]",
+    "    ldp x29, x30, [x1, #0x58]",
+    "    ldp d8, d9, [x1, #0x68]",
+    "    ldp d10, d11, [x1, #0x78]",
+    "    ldp d12, d13, [x1, #0x88]",
+    "    ldp d14, d15, [x1, #0x98]",
+    "    ret",
+    "",
+    ".global coroutine_trampoline",
+    ".p2align 2",
+    "coroutine_trampoline:",
+    "    mov x0, x19",
+    "    blr x20",
+    "    brk #0",
+    ".popsection",
+);
+
+/// CPU context that needs to be saved/restored during context switches
+/// This is the RISC-V LP64D layout
+#[cfg(target_arch = "riscv64")]
+#[repr(C)]
+#[derive(Default)]
+pub struct Context {
+    sp: u64,        // Stack pointer
+    ra: u64,        // Return address: where `ret` jumps to
+    s: [u64; 12],   // Callee-saved s0–s11; s0 doubles as the frame pointer
+    fs: [u64; 12],  // Callee-saved floating point registers fs0–fs11
+}
+
+#[cfg(target_arch = "riscv64")]
+impl Context {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Prepares the first context switch onto a new stack
+    /// Like on aarch64, `ret` jumps to ra, so the new stack stays untouched
+    ///
+    /// # Safety
+    /// `stack_top` must be the end of writable memory that stays alive, and
+    /// `entry` an `extern "C" fn(usize) -> !`.
+    pub unsafe fn start(&mut self, stack_top: usize, entry: usize) {
+        self.sp = (stack_top & !15) as u64;
+        self.ra = coroutine_trampoline as *const () as u64;
+        self.s[2] = entry as u64;
+    }
+
+    /// What the entry function receives when the context first runs
+    pub fn set_argument(&mut self, argument: usize) {
+        self.s[1] = argument as u64;
+    }
+}
+
+// a0 = save, a1 = load. The trampoline finds the coroutine pointer in s1
+// and the entry function in s2.
+#[cfg(target_arch = "riscv64")]
+global_asm!(
+    ".pushsection .text",
+    ".global coroutine_switch_context",
+    ".p2align 2",
+    "coroutine_switch_context:",
+    "    sd sp, 0x00(a0)",
+    "    sd ra, 0x08(a0)",
+    "    sd s0, 0x10(a0)",
+    "    sd s1, 0x18(a0)",
+    "    sd s2, 0x20(a0)",
+    "    sd s3, 0x28(a0)",
+    "    sd s4, 0x30(a0)",
+    "    sd s5, 0x38(a0)",
+    "    sd s6, 0x40(a0)",
+    "    sd s7, 0x48(a0)",
+    "    sd s8, 0x50(a0)",
+    "    sd s9, 0x58(a0)",
+    "    sd s10, 0x60(a0)",
+    "    sd s11, 0x68(a0)",
+    "    fsd fs0, 0x70(a0)",
+    "    fsd fs1, 0x78(a0)",
+    "    fsd fs2, 0x80(a0)",
+    "    fsd fs3, 0x88(a0)",
+    "    fsd fs4, 0x90(a0)",
+    "    fsd fs5, 0x98(a0)",
+    "    fsd fs6, 0xa0(a0)",
+    "    fsd fs7, 0xa8(a0)",
+    "    fsd fs8, 0xb0(a0)",
+    "    fsd fs9, 0xb8(a0)",
+    "    fsd fs10, 0xc0(a0)",
+    "    fsd fs11, 0xc8(a0)",
+    "    ld sp, 0x00(a1)",
+    "    ld ra, 0x08(a1)",
+    "    ld s0, 0x10(a1)",
+    "    ld s1, 0x18(a1)",
+    "    ld s2, 0x20(a1)",
+    "    ld s3, 0x28(a1)",
+    "    ld s4, 0x30(a1)",
+    "    ld s5, 0x38(a1)",
+    "    ld s6, 0x40(a1)",
+    "    ld s7, 0x48(a1)",
+    "    ld s8, 0x50(a1)",
+    "    ld s9, 0x58(a1)",
+    "    ld s10, 0x60(a1)",
+    "    ld s11, 0x68(a1)",
+    "    fld fs0, 0x70(a1)",
+    "    fld fs1, 0x78(a1)",
+    "    fld fs2, 0x80(a1)",
+    "    fld fs3, 0x88(a1)",
+    "    fld fs4, 0x90(a1)",
+    "    fld fs5, 0x98(a1)",
+    "    fld fs6, 0xa0(a1)",
+    "    fld fs7, 0xa8(a1)",
+    "    fld fs8, 0xb0(a1)",
+    "    fld fs9, 0xb8(a1)",
+    "    fld fs10, 0xc0(a1)",
+    "    fld fs11, 0xc8(a1)",
+    "    ret",
+    "",
+    ".global coroutine_trampoline",
+    ".p2align 2",
+    "coroutine_trampoline:",
+    "    mv a0, s1",
+    "    jalr s2",
+    "    unimp",
+    ".popsection",
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Generator, GeneratorState, Stack};
+    use std::ptr;
+
+    /// A bare context bouncing between the test and a fresh stack
+    struct PingPong {
+        main: Context,
+        other: Context,
+        bounces: usize,
+    }
+
+    extern "C" fn bounce(ping_pong: usize) -> ! {
+        let ping_pong = ping_pong as *mut PingPong;
+        unsafe {
+            loop {
+                (*ping_pong).bounces += 1;
+                coroutine_switch_context(ptr::addr_of_mut!((*ping_pong).other), ptr::addr_of!((*ping_pong).main));
+            }
+        }
+    }
+
+    #[test]
+    fn switches_onto_a_fresh_stack_and_back() {
+        let stack = Stack::new(64 * 1024);
+        let mut ping_pong = Box::new(PingPong { main: Context::new(), other: Context::new(), bounces: 0 });
+        let this: *mut PingPong = &mut *ping_pong;
+
+        unsafe {
+            (*this).other.start(stack.top(), bounce as *const () as usize);
+            (*this).other.set_argument(this as usize);
+            for expected in 1..=3 {
+                coroutine_switch_context(ptr::addr_of_mut!((*this).main), ptr::addr_of!((*this).other));
+                assert_eq!((*this).bounces, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn locals_survive_switches_on_both_sides() {
+        // Integers and floats held across yields, on the generator's side
+        // and on ours, end up in callee-saved registers or spilled around
+        // the switch; either way they must come back unchanged
+        let mut generator = Generator::new(|y| {
+            let (mut count, mut total) = (0u64, 0.5f64);
+            for step in 1..=100u64 {
+                count += step;
+                total *= 1.01;
+                y.yield_((count, total));
+            }
+        });
+
+        let (mut count, mut total) = (0u64, 0.5f64);
+        for step in 1..=100u64 {
+            count += step;
+            total *= 1.01;
+            assert_eq!(generator.resume(()), GeneratorState::Yielded((count, total)));
+        }
+        assert_eq!(generator.resume(()), GeneratorState::Complete(()));
+    }
+
+    #[test]
+    fn scheduler_runs_unchanged() {
+        let mut scheduler = crate::Scheduler::new();
+        let workers: Vec<_> = (0..4u64)
+            .map(|id| {
+                scheduler.spawn(move || {
+                    let mut sum = 0.0f64;
+                    for turn in 0..10 {
+                        sum += (id * 10 + turn) as f64;
+                        crate::yield_now();
+                    }
+                    sum
+                })
+            })
+            .collect();
+
+        scheduler.run();
+        let sums: Vec<f64> = workers.into_iter().map(|worker| worker.join().unwrap()).collect();
+        assert_eq!(sums, [45.0, 145.0, 245.0, 345.0]);
+    }
+}
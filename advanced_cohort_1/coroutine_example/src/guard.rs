@@ -0,0 +1,147 @@
+//! Stack overflow detection for coroutine stacks
+//! Every Stack has a PROT_NONE guard page right below its usable memory, so
+//! running off the end faults instead of scribbling over the heap.
+//! This module turns that fault into a readable report:
+//! 1. The scheduler records which stack it is switching onto
+//! 2. A SIGSEGV handler, running on an alternate signal stack, compares the
+//!    fault address with that stack's guard page
+//! 3. On a match it names the culprit and aborts; anything else is handed
+//!    to whichever handler was installed before us
+
+use std::cell::Cell;
+use std::fmt::Write;
+use std::mem;
+use std::ptr;
+use std::sync::Once;
+
+/// Size of the alternate stack the signal handler runs on
+/// The overflowing stack itself is unusable, so the handler needs its own
+const SIGNAL_STACK_SIZE: usize = 64 * 1024;
+
+/// Describes the stack that is currently executing on this thread
+#[derive(Clone, Copy)]
+pub struct ActiveStack {
+    pub owner: Option<usize>,   // Coroutine id, None for generators
+    pub guard_start: usize,     // First byte of the guard page
+    pub guard_end: usize,       // One past the last byte of the guard page
+    pub size: usize,            // Usable stack size in bytes
+}
+
+thread_local! {
+    // Plain Copy data with a const initializer, so reading it from the
+    // signal handler never allocates or runs lazy initialization
+    static ACTIVE: Cell<Option<ActiveStack>> = const { Cell::new(None) };
+    static ALT_STACK_READY: Cell<bool> = const { Cell::new(false) };
+}
+
+static INSTALL: Once = Once::new();
+static mut PREVIOUS_ACTION: mem::MaybeUninit<libc::sigaction> = mem::MaybeUninit::uninit();
+
+/// Marks `stack` as the one about to run, returning whatever ran before
+/// Callers restore the returned value once control comes back to them.
+pub fn enter(stack: ActiveStack) -> Option<ActiveStack> {
+    install();
+    ACTIVE.with(|active| active.replace(Some(stack)))
+}
+
+/// Restores the stack that was active before the matching enter()
+pub fn leave(previous: Option<ActiveStack>) {
+    ACTIVE.with(|active| active.set(previous));
+}
+
+/// Installs the SIGSEGV handler once per process and an alternate signal
+/// stack once per thread
+fn install() {
+    INSTALL.call_once(|| unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handle_segv as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        let previous = ptr::addr_of_mut!(PREVIOUS_ACTION) as *mut libc::sigaction;
+        if libc::sigaction(libc::SIGSEGV, &action, previous) != 0 {
+            panic!("failed to install the stack overflow handler");
+        }
+    });
+
+    if ALT_STACK_READY.with(|ready| ready.replace(true)) {
+        return;
+    }
+
+    unsafe {
+        // The standard library already gives most threads one; keep it if so
+        let mut current: libc::stack_t = mem::zeroed();
+        libc::sigaltstack(ptr::null(), &mut current);
+        if current.ss_flags & libc::SS_DISABLE == 0 {
+            return;
+        }
+
+        // Leaked on purpose: the signal stack must outlive the thread's use of it
+        let memory = Box::leak(vec![0u8; SIGNAL_STACK_SIZE].into_boxed_slice());
+        let alt = libc::stack_t {
+            ss_sp: memory.as_mut_ptr() as *mut libc::c_void,
+            ss_flags: 0,
+            ss_size: SIGNAL_STACK_SIZE,
+        };
+        libc::sigaltstack(&alt, ptr::null_mut());
+    }
+}
+
+extern "C" fn handle_segv(signal: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    let address = unsafe { (*info).si_addr() } as usize;
+    let active = ACTIVE.with(|active| active.get());
+
+    match active {
+        Some(stack) if (stack.guard_start..stack.guard_end).contains(&address) => {
+            report_overflow(&stack, address);
+            unsafe { libc::abort() };
+        }
+        _ => unsafe {
+            // Not ours: put the previous handler back and return, so the
+            // faulting instruction runs again and gets handled by it
+            let previous = ptr::addr_of!(PREVIOUS_ACTION) as *const libc::sigaction;
+            libc::sigaction(signal, previous, ptr::null_mut());
+        },
+    }
+}
+
+/// Writes the overflow report to stderr without allocating
+/// Only async-signal-safe calls are allowed here, hence the fixed buffer.
+fn report_overflow(stack: &ActiveStack, address: usize) {
+    let mut message = SignalBuffer::new();
+    let _ = match stack.owner {
+        Some(id) => write!(message, "\nfatal: coroutine {} overflowed its stack", id),
+        None => write!(message, "\nfatal: generator overflowed its stack"),
+    };
+    let _ = writeln!(
+        message,
+        " ({} bytes, fault at {:#x} in guard page {:#x}..{:#x})",
+        stack.size, address, stack.guard_start, stack.guard_end
+    );
+
+    unsafe {
+        libc::write(libc::STDERR_FILENO, message.buf.as_ptr() as *const libc::c_void, message.len);
+    }
+}
+
+/// Fixed-size formatting target usable from a signal handler
+struct SignalBuffer {
+    buf: [u8; 256],
+    len: usize,
+}
+
+impl SignalBuffer {
+    fn new() -> Self {
+        SignalBuffer { buf: [0; 256], len: 0 }
+    }
+}
+
+impl Write for SignalBuffer {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        // Truncate rather than fail: a partial report beats none
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
@@ -0,0 +1,2489 @@
+//! Educational Coroutine Implementation
+//! This implementation demonstrates key concepts of coroutines:
+//! 1. Stack Management
+//! 2. Context Switching
+//! 3. Basic Scheduling
+//! 4. State Management
+//! 
+//! Note: This is a simplified implementation for educational purposes.
+//! Production implementations would need additional safety checks and optimizations.
+
+mod bridge;
+mod channel;
+mod combinators;
+mod context;
+mod guard;
+mod multi;
+mod net;
+mod policy;
+mod pool;
+mod reactor;
+mod scope;
+mod timer;
+mod trace;
+
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Wake, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use context::{coroutine_switch_context, Context};
+use reactor::Reactor;
+use timer::{TimerId, TimerWheel};
+use trace::Tracer;
+
+pub use channel::{bounded, unbounded, Receiver, RecvError, SendError, Sender};
+pub use combinators::{ChainWith, IntoStream, MapYield, PipeInto, Yields, ZipWith};
+pub use multi::MultiScheduler;
+pub use net::{TcpListener, TcpStream};
+pub use policy::{EarliestDeadlineFirst, FairShare, PriorityQueue, RoundRobin, SchedulingPolicy, Task};
+pub use pool::{PoolStats, StackPool};
+pub use scope::Scope;
+pub use trace::CoroutineStats;
+
+
+/// Default size of each coroutine's stack
+/// Can be overridden per scheduler or per spawn
+const STACK_SIZE: usize = 1024 * 1024 * 2; // 2MB
+
+thread_local! {
+    /// The coroutine currently executing on this thread, if any
+    /// This is how yield_now() finds the context it has to save
+    static CURRENT: Cell<Option<*mut dyn AnyCoroutine>> = const { Cell::new(None) };
+
+    /// Timers of the scheduler running on this thread, for sleep()
+    static TIMERS: RefCell<Option<Rc<RefCell<TimerWheel>>>> = const { RefCell::new(None) };
+}
+
+/// Represents all possible states a coroutine can be in
+/// This is crucial for understanding coroutine lifecycle
+#[derive(Debug)]
+pub enum CoroutineState {
+    Ready,      // Initial state, ready to run
+    Running,    // Currently executing
+    Suspended,  // Temporarily paused, ready to continue
+    Blocked,    // Waiting for an event; resumes once its Waker is woken
+    Complete,   // Finished execution
+    Panicked(Box<dyn Any + Send>),  // Body panicked; carries the payload
+    Cancelled,  // Unwound on request of its JoinHandle
+}
+
+impl CoroutineState {
+    /// The variant's name, without any payload
+    pub fn name(&self) -> &'static str {
+        match self {
+            CoroutineState::Ready => "Ready",
+            CoroutineState::Running => "Running",
+            CoroutineState::Suspended => "Suspended",
+            CoroutineState::Blocked => "Blocked",
+            CoroutineState::Complete => "Complete",
+            CoroutineState::Panicked(_) => "Panicked",
+            CoroutineState::Cancelled => "Cancelled",
+        }
+    }
+
+    /// Whether the coroutine is done for good, however it ended
+    pub fn is_finished(&self) -> bool {
+        matches!(self, CoroutineState::Complete | CoroutineState::Panicked(_) | CoroutineState::Cancelled)
+    }
+}
+
+/// Manages the actual memory used for coroutine execution
+/// Demonstrates stack allocation and safety considerations
+///
+/// Memory layout (addresses grow upwards, the stack grows downwards):
+///
+/// ```text
+/// +-------------------+ <- base + size (initial stack pointer)
+/// |   usable stack    |
+/// +-------------------+ <- base
+/// | guard (PROT_NONE) |
+/// +-------------------+ <- base - guard_size (start of the mapping)
+/// ```
+struct Stack {
+    base: *mut u8,      // Lowest usable address, just above the guard page
+    size: usize,        // Usable size of the stack
+    guard_size: usize,  // Size of the inaccessible region below it
+}
+
+impl Stack {
+    /// Maps a new stack with a guard page underneath
+    /// Important teaching points:
+    /// - Page alignment requirements
+    /// - Memory protection as a safety net
+    /// - Resource management
+    fn new(size: usize) -> Self {
+        // mmap works in whole pages, so round the request up
+        let size = Self::round_to_pages(size);
+        let guard_size = Self::page_size();
+
+        let mapping = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size + guard_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_STACK,
+                -1,
+                0,
+            )
+        };
+        if mapping == libc::MAP_FAILED {
+            panic!("failed to map coroutine stack: {}", std::io::Error::last_os_error());
+        }
+
+        // Any access to the lowest page now faults instead of silently
+        // running into whatever memory happens to sit below the stack
+        if unsafe { libc::mprotect(mapping, guard_size, libc::PROT_NONE) } != 0 {
+            panic!("failed to protect guard page: {}", std::io::Error::last_os_error());
+        }
+
+        let base = unsafe { (mapping as *mut u8).add(guard_size) };
+        Stack { base, size, guard_size }
+    }
+
+    fn page_size() -> usize {
+        unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+    }
+
+    /// The size a stack requested with `size` bytes actually ends up with
+    fn round_to_pages(size: usize) -> usize {
+        let page = Self::page_size();
+        size.max(1).div_ceil(page) * page
+    }
+
+    /// Describes this stack to the overflow handler
+    fn active(&self, owner: Option<usize>) -> guard::ActiveStack {
+        guard::ActiveStack {
+            owner,
+            guard_start: self.base as usize - self.guard_size,
+            guard_end: self.base as usize,
+            size: self.size,
+        }
+    }
+
+    /// One past the highest usable address; the stack grows down from here
+    fn top(&self) -> usize {
+        self.base as usize + self.size
+    }
+}
+
+// A Stack is just owned memory; whoever holds it may hand it to another
+// thread, as long as no code is still running on it. The same goes for
+// anything suspended on it, which is why Generator opts back out of Send.
+unsafe impl Send for Stack {}
+
+// Proper cleanup is crucial for safety
+impl Drop for Stack {
+    fn drop(&mut self) {
+        unsafe {
+            let mapping = self.base.sub(self.guard_size);
+            libc::munmap(mapping as *mut libc::c_void, self.size + self.guard_size);
+        }
+    }
+}
+
+/// Source of unique coroutine ids, used when reporting problems
+static NEXT_COROUTINE_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// The core coroutine structure
+/// Type parameter F represents the function/closure to be executed
+pub struct Coroutine<F> {
+    id: usize,
+    stack: Stack,
+    context: Context,
+    caller: Context,   // Scheduler context to return to on yield/completion
+    state: CoroutineState,
+    func: Option<F>,
+    join: Option<Arc<dyn JoinSlot>>,  // Where failures are reported, if anyone listens
+    waker: Option<Waker>,             // Puts this coroutine back on its scheduler's queue
+    cancel: Option<Arc<CancelFlag>>,  // Set by JoinHandle::cancel()
+}
+
+impl<F: FnOnce()> Coroutine<F> {
+    /// Creates a new coroutine from a function
+    pub fn new(func: F) -> Self {
+        Self::with_stack(func, Stack::new(STACK_SIZE))
+    }
+
+    /// Creates a coroutine running on an already allocated stack
+    fn with_stack(func: F, stack: Stack) -> Self {
+        let mut coro = Coroutine {
+            id: NEXT_COROUTINE_ID.fetch_add(1, Ordering::Relaxed),
+            stack,
+            context: Context::new(),
+            caller: Context::new(),
+            state: CoroutineState::Ready,
+            func: Some(func),
+            join: None,
+            waker: None,
+            cancel: None,
+        };
+        
+        // Set up the initial stack state
+        coro.initialize_stack();
+        coro
+    }
+
+    /// Prepares the stack for first execution
+    /// The trampoline will call coroutine_entry with this coroutine's address
+    fn initialize_stack(&mut self) {
+        unsafe {
+            self.context.start(self.stack.top(), coroutine_entry::<F> as *const () as usize);
+        }
+    }
+
+    fn cancel_requested(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|cancel| cancel.is_requested())
+    }
+}
+
+/// Entry point of every coroutine, running on the coroutine's own stack
+/// Reached through coroutine_trampoline on the first switch and never returns:
+/// once the body finishes it switches back to the scheduler for good.
+///
+/// A panic must never unwind past this frame, there is nothing above it but
+/// the trampoline. It is caught here and parked in the coroutine's state.
+extern "C" fn coroutine_entry<F: FnOnce()>(coro: *mut Coroutine<F>) -> ! {
+    unsafe {
+        let outcome = match (*coro).func.take() {
+            // Cancelled before it ever ran: dropping the closure is enough
+            Some(_) if (*coro).cancel_requested() => Err(Box::new(Cancelled) as Box<dyn Any + Send>),
+            Some(f) => panic::catch_unwind(AssertUnwindSafe(f)),
+            None => Ok(()),
+        };
+        (*coro).state = match outcome {
+            Ok(()) => CoroutineState::Complete,
+            Err(payload) if payload.is::<Cancelled>() => CoroutineState::Cancelled,
+            Err(payload) => CoroutineState::Panicked(payload),
+        };
+        coroutine_switch_context(
+            ptr::addr_of_mut!((*coro).context),
+            ptr::addr_of!((*coro).caller),
+        );
+    }
+    unreachable!("completed coroutine was resumed");
+}
+
+/// Scheduler for managing multiple coroutines
+/// Demonstrates:
+/// - Basic scheduling concepts
+/// - Queue-based management
+/// - Pluggable scheduling policies (round-robin by default)
+pub struct Scheduler {
+    ready_queue: Box<dyn SchedulingPolicy>,  // Runnable coroutines, ordered by the policy
+    current: Option<Task>,
+    blocked: HashMap<usize, Task>,  // Parked until woken, by id
+    wakeups: Arc<WakeQueue>,    // Ids woken since we last looked
+    woken_early: HashSet<usize>,  // Woken before they actually blocked
+    timers: Rc<RefCell<TimerWheel>>,  // Coroutines sleeping until a deadline
+    reactor: Rc<Reactor>,       // Coroutines waiting for socket I/O
+    tracer: Tracer,             // Per-coroutine statistics and timeline
+    stacks: StackPool,          // Recycles stacks of finished coroutines
+    default_stack_size: usize,  // Used when a spawn doesn't ask otherwise
+}
+
+/// What Scheduler::enter() replaced on this thread
+struct Entered {
+    timers: Option<Rc<RefCell<TimerWheel>>>,
+    reactor: Option<Rc<Reactor>>,
+}
+
+/// Wakeups waiting to be processed by a scheduler
+/// Wakers may fire from any thread, hence the mutex. The eventfd lets an
+/// idle scheduler sleep in its reactor until one does.
+#[derive(Default)]
+struct WakeQueue {
+    ids: Mutex<Vec<usize>>,
+    eventfd: EventFd,  // Readable while wakeups may be pending
+}
+
+impl WakeQueue {
+    fn push(&self, id: usize) {
+        let mut ids = self.ids.lock().unwrap();
+        ids.push(id);
+
+        // Only the first id since the last drain needs to signal: the
+        // scheduler always drains the ids after resetting the eventfd
+        if ids.len() == 1 {
+            self.eventfd.signal();
+        }
+    }
+
+    /// Clears the eventfd once the reactor saw it
+    fn reset(&self) {
+        self.eventfd.reset();
+    }
+}
+
+/// A non-blocking eventfd: readable from signal() until the next reset()
+struct EventFd(OwnedFd);
+
+impl Default for EventFd {
+    fn default() -> Self {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        assert!(fd >= 0, "eventfd failed: {}", std::io::Error::last_os_error());
+        EventFd(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+}
+
+impl EventFd {
+    fn signal(&self) {
+        let one: u64 = 1;
+        unsafe { libc::write(self.0.as_raw_fd(), &one as *const u64 as *const libc::c_void, 8) };
+    }
+
+    fn reset(&self) {
+        let mut count: u64 = 0;
+        unsafe { libc::read(self.0.as_raw_fd(), &mut count as *mut u64 as *mut libc::c_void, 8) };
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+/// What sits behind every coroutine's std::task::Waker
+/// Waking just records the id; the scheduler moves the coroutine from its
+/// blocked set back to the ready queue the next time it looks.
+struct CoroutineWaker {
+    id: usize,
+    queue: Arc<WakeQueue>,
+}
+
+impl Wake for CoroutineWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.queue.push(self.id);
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Configures a Scheduler before it is created
+/// Start from Scheduler::builder() and finish with build()
+pub struct SchedulerBuilder {
+    stack_size: usize,
+    stacks: Option<StackPool>,
+    pool_capacity: usize,
+    policy: Option<Box<dyn SchedulingPolicy>>,
+    tracing: bool,
+}
+
+impl SchedulerBuilder {
+    /// Default stack size for coroutines spawned without SpawnOptions
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = size;
+        self
+    }
+
+    /// Max idle stacks of each size kept for reuse (0 disables pooling)
+    pub fn pool_capacity(mut self, capacity: usize) -> Self {
+        self.pool_capacity = capacity;
+        self
+    }
+
+    /// Shares an existing pool instead of creating a private one
+    pub fn stack_pool(mut self, pool: StackPool) -> Self {
+        self.stacks = Some(pool);
+        self
+    }
+
+    /// Decides which runnable coroutine goes next (RoundRobin by default)
+    pub fn policy(mut self, policy: impl SchedulingPolicy + 'static) -> Self {
+        self.policy = Some(Box::new(policy));
+        self
+    }
+
+    /// Records a timeline of every time slice and wait, for
+    /// Scheduler::write_chrome_trace (off by default)
+    pub fn tracing(mut self, enabled: bool) -> Self {
+        self.tracing = enabled;
+        self
+    }
+
+    pub fn build(self) -> Scheduler {
+        let wakeups = Arc::new(WakeQueue::default());
+        Scheduler {
+            ready_queue: self.policy.unwrap_or_else(|| Box::new(RoundRobin::new())),
+            current: None,
+            blocked: HashMap::new(),
+            woken_early: HashSet::new(),
+            timers: Rc::new(RefCell::new(TimerWheel::default())),
+            reactor: Rc::new(Reactor::new(wakeups.clone()).expect("failed to create the epoll reactor")),
+            tracer: Tracer::new(self.tracing),
+            wakeups,
+            stacks: self.stacks.unwrap_or_else(|| StackPool::new(self.pool_capacity)),
+            default_stack_size: self.stack_size,
+        }
+    }
+}
+
+/// Per-spawn settings, for when the scheduler's defaults don't fit
+#[derive(Debug, Clone, Default)]
+pub struct SpawnOptions {
+    stack_size: Option<usize>,
+    priority: i32,
+    deadline: Option<Instant>,
+    weight: Option<u32>,
+}
+
+impl SpawnOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stack size for this coroutine only
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = Some(size);
+        self
+    }
+
+    /// Priority under PriorityQueue; higher runs first, default 0
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Deadline under EarliestDeadlineFirst
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// CPU share under FairShare, relative to other coroutines' weights
+    ///
+    /// Panics if `weight` is 0.
+    pub fn weight(mut self, weight: u32) -> Self {
+        assert!(weight > 0, "a coroutine's weight must be at least 1");
+        self.weight = Some(weight);
+        self
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::builder().build()
+    }
+
+    pub fn builder() -> SchedulerBuilder {
+        SchedulerBuilder {
+            stack_size: STACK_SIZE,
+            stacks: None,
+            pool_capacity: pool::DEFAULT_POOL_CAPACITY,
+            policy: None,
+            tracing: false,
+        }
+    }
+
+    /// Adds a new coroutine to the scheduler
+    /// The returned handle gives access to the closure's result
+    pub fn spawn<F, T>(&mut self, func: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + 'static,
+        T: 'static,
+    {
+        self.spawn_with(SpawnOptions::new(), func)
+    }
+
+    /// Adds a new coroutine using the given per-spawn options
+    pub fn spawn_with<F, T>(&mut self, options: SpawnOptions, func: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + 'static,
+        T: 'static,
+    {
+        let size = options.stack_size.unwrap_or(self.default_stack_size);
+        let stack = self.stacks.acquire(size);
+
+        let (coro, handle) = prepare_coroutine(func, stack, &self.wakeups);
+        let weight = options.weight.unwrap_or(policy::DEFAULT_WEIGHT);
+        self.enqueue(Task::new(coro, options.priority, options.deadline, weight));
+        handle
+    }
+
+    /// Hands a freshly spawned task to the policy
+    fn enqueue(&mut self, task: Task) {
+        self.tracer.spawned(task.id());
+        self.ready_queue.push(task);
+    }
+
+    /// Hit/miss counters of the scheduler's stack pool
+    pub fn pool_stats(&self) -> PoolStats {
+        self.stacks.stats()
+    }
+
+    /// Statistics of every coroutine and future spawned so far, by id
+    pub fn coroutine_stats(&self) -> Vec<CoroutineStats> {
+        self.tracer.stats()
+    }
+
+    /// Writes the recorded timeline as Chrome trace-event JSON
+    /// Only has spans if the scheduler was built with tracing(true).
+    pub fn write_chrome_trace(&self, out: impl std::io::Write) -> std::io::Result<()> {
+        self.tracer.write_chrome_trace(out)
+    }
+
+    /// Main scheduling loop
+    /// Teaching points:
+    /// - Scheduling algorithms
+    /// - Coroutine state transitions
+    /// - Queue management
+    ///
+    /// When only sleeping coroutines or ones waiting for socket I/O are
+    /// left, the thread idles until a timer is due or an fd is ready.
+    /// Returns once nothing is runnable, no timers are armed and nobody
+    /// waits for I/O. Coroutines that are still Blocked stay parked; if
+    /// something wakes them later, another run() picks them up again.
+    pub fn run(&mut self) {
+        let entered = self.enter();
+
+        loop {
+            if self.run_once() {
+                continue;
+            }
+
+            // Everyone left is waiting; if a timer or an fd will end that,
+            // it's the only moment the whole thread may sleep
+            match self.next_deadline() {
+                None if !self.reactor.has_waiters() => break,
+                deadline => self.wait_for_events(deadline),
+            }
+        }
+
+        self.leave(entered);
+    }
+
+    /// Lets sleep() and socket I/O inside our coroutines find our timers
+    /// and reactor; returns what was installed before, for leave()
+    fn enter(&self) -> Entered {
+        Entered {
+            timers: TIMERS.with(|t| t.replace(Some(self.timers.clone()))),
+            reactor: reactor::enter(self.reactor.clone()),
+        }
+    }
+
+    fn leave(&self, entered: Entered) {
+        TIMERS.with(|t| t.replace(entered.timers));
+        reactor::leave(entered.reactor);
+    }
+
+    /// Sleeps in the reactor until an fd is ready, a Waker fires or
+    /// `deadline` (if any) has passed
+    fn wait_for_events(&self, deadline: Option<Instant>) {
+        let timeout = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        self.reactor.poll(timeout).expect("epoll_wait failed");
+    }
+
+    /// One step of the scheduling loop: fires due timers, processes
+    /// wakeups and runs the next coroutine for one time slice
+    /// Returns false if nothing was runnable.
+    fn run_once(&mut self) -> bool {
+        // Coroutines whose deadline or event arrived become runnable again
+        if !self.timers.borrow().is_empty() {
+            self.timers.borrow_mut().advance(Instant::now());
+        }
+        // Same for I/O, without blocking: other coroutines are still runnable
+        if self.reactor.has_waiters() {
+            self.reactor.poll(Some(Duration::ZERO)).expect("epoll_wait failed");
+        }
+        self.process_wakeups();
+
+        let Some(task) = self.ready_queue.pop() else {
+            return false;
+        };
+        self.current = Some(task);
+        
+        let mut current = self.current.take();
+        if let Some(ref mut task) = current {
+            // This is where actual context switch happens
+            let started = Instant::now();
+            self.tracer.resumed(task.id(), started);
+            unsafe {
+                self.context_switch(task.coroutine());
+            }
+            let stopped = Instant::now();
+            self.tracer.stopped(task.id(), task.state(), stopped);
+            self.ready_queue.charge(task, stopped - started);
+        }
+        self.current = current;
+        
+        // Handle coroutine after execution
+        if let Some(task) = self.current.take() {
+            match task.state() {
+                CoroutineState::Suspended => {
+                    // Coroutine yielded, put it back in queue
+                    self.ready_queue.push(task);
+                }
+                CoroutineState::Blocked => {
+                    // Coroutine waits for an event; unless that already
+                    // happened, it sits out until its Waker fires
+                    if self.woken_early.remove(&task.id()) {
+                        self.tracer.woken(task.id(), Instant::now());
+                        self.ready_queue.push(task);
+                    } else {
+                        self.blocked.insert(task.id(), task);
+                    }
+                }
+                CoroutineState::Complete | CoroutineState::Panicked(_) | CoroutineState::Cancelled => {
+                    // Coroutine finished (a panic only ends this one),
+                    // keep its stack for the next one
+                    self.woken_early.remove(&task.id());
+                    if let Some(stack) = task.into_coroutine().finish() {
+                        self.stacks.release(stack);
+                    }
+                }
+                _ => unreachable!(),
+            }
+        }
+        true
+    }
+
+    /// When the earliest sleeping coroutine is due, if any
+    fn next_deadline(&self) -> Option<Instant> {
+        self.timers.borrow().next_deadline()
+    }
+
+    /// Moves woken coroutines from the blocked set to the ready queue
+    fn process_wakeups(&mut self) {
+        let woken = mem::take(&mut *self.wakeups.ids.lock().unwrap());
+
+        for id in woken {
+            match self.blocked.remove(&id) {
+                Some(task) => {
+                    self.tracer.woken(id, Instant::now());
+                    self.ready_queue.push(task);
+                }
+                // Not blocked (yet): remember, so its next park() returns
+                None => {
+                    self.woken_early.insert(id);
+                }
+            }
+        }
+    }
+
+    /// Performs the actual context switch
+    /// Control leaves the scheduler's stack here and only comes back once
+    /// the coroutine has either yielded or completed
+    unsafe fn context_switch(&mut self, coro: &mut (dyn AnyCoroutine + 'static)) {
+        switch_into(coro);
+    }
+}
+
+/// Runs `coro` on the calling thread until it yields, blocks or finishes
+/// Shared by every scheduler flavour, single- or multi-threaded.
+unsafe fn switch_into(coro: &mut (dyn AnyCoroutine + 'static)) {
+    coro.set_state(CoroutineState::Running);
+
+    // Remember the previous value so schedulers can be nested
+    let previous = CURRENT.with(|c| c.replace(Some(coro as *mut _)));
+    coro.resume();
+    CURRENT.with(|c| c.set(previous));
+}
+
+/// Wraps `func` into a coroutine wired to a JoinHandle and a Waker
+/// The coroutine itself only ever sees FnOnce(); the result travels
+/// through a slot shared with the handle.
+fn prepare_coroutine<F, T>(
+    func: F,
+    stack: Stack,
+    wakeups: &Arc<WakeQueue>,
+) -> (Box<dyn AnyCoroutine>, JoinHandle<T>)
+where
+    F: FnOnce() -> T + 'static,
+    T: 'static,
+{
+    // SAFETY: nothing the coroutine holds borrows anything
+    unsafe { prepare_scoped_coroutine(func, stack, wakeups) }
+}
+
+/// prepare_coroutine() for closures and results that may borrow for 'a
+///
+/// # Safety
+/// The scheduler stores coroutines as if they were 'static. The caller
+/// must make sure the coroutine finishes, or is cancelled, before 'a ends.
+unsafe fn prepare_scoped_coroutine<'a, F, T>(
+    func: F,
+    stack: Stack,
+    wakeups: &Arc<WakeQueue>,
+) -> (Box<dyn AnyCoroutine>, JoinHandle<T>)
+where
+    F: FnOnce() -> T + 'a,
+    T: 'a,
+{
+    let state = Arc::new(Mutex::new(JoinState { result: None, waiter: None }));
+    let slot = state.clone();
+    let body = move || {
+        let value = func();
+        slot.lock().unwrap().complete(Ok(value));
+    };
+
+    let mut coro = Box::new(Coroutine::with_stack(body, stack));
+    let waker = Waker::from(Arc::new(CoroutineWaker {
+        id: coro.id,
+        queue: wakeups.clone(),
+    }));
+    let cancel = Arc::new(CancelFlag::new(waker.clone()));
+    let join: Arc<dyn JoinSlot + 'a> = state.clone();
+    coro.join = Some(mem::transmute::<Arc<dyn JoinSlot + 'a>, Arc<dyn JoinSlot>>(join));
+    coro.waker = Some(waker);
+    coro.cancel = Some(cancel.clone());
+
+    let id = coro.id;
+    let coro: Box<dyn AnyCoroutine + 'a> = coro;
+    let coro = mem::transmute::<Box<dyn AnyCoroutine + 'a>, Box<dyn AnyCoroutine>>(coro);
+    (coro, JoinHandle { id, state, cancel })
+}
+
+/// Why a coroutine did not produce a result
+#[derive(Debug)]
+pub enum JoinError {
+    /// The body panicked; holds the value it panicked with
+    Panicked(Box<dyn Any + Send>),
+    /// JoinHandle::cancel() stopped it before it finished
+    Cancelled,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Panicked(payload) => {
+                // panic!() payloads are almost always one of these two
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("<non-string payload>");
+                write!(f, "coroutine panicked: {}", message)
+            }
+            JoinError::Cancelled => write!(f, "coroutine was cancelled"),
+        }
+    }
+}
+
+/// Owned permission to wait for a coroutine and take its result
+/// Works both from another coroutine (which blocks while waiting) and
+/// from the thread that called Scheduler::run, once run() has returned.
+pub struct JoinHandle<T> {
+    id: usize,
+    state: Arc<Mutex<JoinState<T>>>,
+    cancel: Arc<CancelFlag>,
+}
+
+/// Shared by a JoinHandle and its coroutine to request cancellation
+struct CancelFlag {
+    requested: AtomicBool,
+    waker: Waker,  // Gets a blocked coroutine running so it notices
+}
+
+impl CancelFlag {
+    fn new(waker: Waker) -> Self {
+        CancelFlag { requested: AtomicBool::new(false), waker }
+    }
+
+    fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::Acquire)
+    }
+
+    /// Flags the coroutine and wakes it, in case it is blocked
+    fn request(&self) {
+        self.requested.store(true, Ordering::Release);
+        self.waker.wake_by_ref();
+    }
+}
+
+/// Panic payload that unwinds a cancelled coroutine or a dropped generator
+/// Raised with resume_unwind, so no panic message gets printed.
+struct Cancelled;
+
+/// What a JoinHandle shares with its coroutine
+struct JoinState<T> {
+    result: Option<Result<T, JoinError>>,
+    waiter: Option<Waker>,  // Coroutine blocked in join(), if any
+}
+
+impl<T> JoinState<T> {
+    /// Stores the outcome and wakes whoever is waiting for it
+    fn complete(&mut self, result: Result<T, JoinError>) {
+        self.result = Some(result);
+        if let Some(waiter) = self.waiter.take() {
+            waiter.wake();
+        }
+    }
+}
+
+/// The scheduler's view of a JoinHandle's slot
+/// It doesn't know the result type, but it can still report a failure.
+trait JoinSlot {
+    fn fail(&self, error: JoinError);
+    /// Takes the payload of a panic nobody has joined yet
+    fn take_panic(&self) -> Option<Box<dyn Any + Send>>;
+}
+
+impl<T> JoinSlot for Mutex<JoinState<T>> {
+    fn fail(&self, error: JoinError) {
+        self.lock().unwrap().complete(Err(error));
+    }
+
+    fn take_panic(&self) -> Option<Box<dyn Any + Send>> {
+        let mut state = self.lock().unwrap();
+        match state.result.take() {
+            Some(Err(JoinError::Panicked(payload))) => Some(payload),
+            result => {
+                state.result = result;
+                None
+            }
+        }
+    }
+}
+
+impl<T> JoinHandle<T> {
+    /// Id of the coroutine, as used in CoroutineStats and traces
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Asks the coroutine to stop
+    /// It unwinds at its next suspension point (yield_now, park, sleep, a
+    /// blocking channel or socket operation), so Drop runs for everything
+    /// it holds, and join() then reports JoinError::Cancelled. A coroutine
+    /// that hasn't started never runs; one that finishes without reaching
+    /// another suspension point keeps its result.
+    pub fn cancel(&self) {
+        if !self.is_finished() {
+            self.cancel.request();
+        }
+    }
+
+    /// Whether the coroutine has finished, successfully or not
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().unwrap().result.is_some()
+    }
+
+    /// Waits for the coroutine to finish and returns its result
+    /// Inside a coroutine this blocks until the result is there.
+    /// A panic in the coroutine comes back as JoinError::Panicked.
+    ///
+    /// Panics when called outside of a coroutine before the coroutine
+    /// finished, since nothing could ever make progress on it.
+    pub fn join(self) -> Result<T, JoinError> {
+        loop {
+            let mut state = self.state.lock().unwrap();
+            if let Some(result) = state.result.take() {
+                return result;
+            }
+
+            if !in_coroutine() {
+                panic!("joined an unfinished coroutine outside the scheduler; call Scheduler::run first");
+            }
+            state.waiter = Some(current_waker());
+            drop(state);
+            park();
+        }
+    }
+}
+
+/// The coroutine running on this thread, if any
+///
+/// Never inlined on purpose: the compiler assumes a function stays on one
+/// thread and may reuse a thread-local's address computed earlier in the
+/// same frame. A coroutine on a multi-threaded scheduler can resume on a
+/// different thread, so each lookup gets a fresh call.
+#[inline(never)]
+fn current_coroutine() -> Option<*mut dyn AnyCoroutine> {
+    CURRENT.with(|c| c.get())
+}
+
+/// Whether the caller is running inside a coroutine on this thread
+fn in_coroutine() -> bool {
+    current_coroutine().is_some()
+}
+
+/// Gives up the CPU from inside a coroutine
+/// The coroutine is marked Suspended, goes to the back of the ready queue
+/// and later continues right after this call, with all its locals intact.
+///
+/// Panics when called outside of a coroutine, since there is no scheduler
+/// to return to.
+pub fn yield_now() {
+    let current = current_coroutine().expect("yield_now() called outside of a coroutine");
+
+    unsafe {
+        check_cancelled(current);
+        (*current).suspend(CoroutineState::Suspended);
+        check_cancelled(current);
+    }
+}
+
+/// Blocks the current coroutine until its Waker is woken
+/// This is the building block for anything that waits on an event:
+/// register current_waker() where the event will see it, then park().
+/// Wakeups can be spurious, so always re-check the condition afterwards.
+///
+/// Panics when called outside of a coroutine.
+pub fn park() {
+    let current = current_coroutine().expect("park() called outside of a coroutine");
+
+    unsafe {
+        check_cancelled(current);
+        (*current).suspend(CoroutineState::Blocked);
+        check_cancelled(current);
+    }
+}
+
+/// Unwinds the current coroutine if its JoinHandle asked for cancellation
+/// Skipped while already unwinding: Drop impls may well yield, and a
+/// second panic would abort the process.
+unsafe fn check_cancelled(current: *mut dyn AnyCoroutine) {
+    if (*current).cancel_requested() && !thread::panicking() {
+        panic::resume_unwind(Box::new(Cancelled));
+    }
+}
+
+/// Puts the current coroutine to sleep for at least `duration`
+/// Unlike thread::sleep, other coroutines keep running in the meantime.
+///
+/// Panics when called outside of a coroutine running on a Scheduler.
+pub fn sleep(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    let _timer = ArmedTimer(arm_timer(deadline, current_waker()));
+
+    // The timer stays armed across spurious wakeups, so just park again
+    while Instant::now() < deadline {
+        park();
+    }
+}
+
+/// Disarms a sleep() timer when the sleeper is cancelled
+/// Otherwise it would keep the scheduler waiting for a coroutine that is
+/// long gone. After a normal wakeup the timer has fired and this is a no-op.
+struct ArmedTimer(TimerId);
+
+impl Drop for ArmedTimer {
+    fn drop(&mut self) {
+        disarm_timer(self.0);
+    }
+}
+
+/// Registers a timer with the scheduler running on this thread
+/// Not inlined for the same reason as current_coroutine().
+#[inline(never)]
+fn arm_timer(deadline: Instant, waker: Waker) -> TimerId {
+    TIMERS.with(|timers| {
+        let timers = timers.borrow();
+        let timers = timers.as_ref().expect("sleep() called outside of a running Scheduler");
+        let timer = timers.borrow_mut().insert(deadline, waker);
+        timer
+    })
+}
+
+/// Removes a timer armed by the current coroutine, if it's still there
+/// On a MultiScheduler the coroutine may have moved to another worker
+/// since; this worker's wheel then passes the timer on to its owner.
+#[inline(never)]
+fn disarm_timer(timer: TimerId) {
+    TIMERS.with(|timers| {
+        if let Some(timers) = timers.borrow().as_ref() {
+            timers.borrow_mut().remove(timer);
+        }
+    });
+}
+
+/// Returns the Waker that makes the current coroutine runnable again
+///
+/// Panics when called outside of a coroutine spawned on a Scheduler.
+pub fn current_waker() -> Waker {
+    let current = current_coroutine().expect("current_waker() called outside of a coroutine");
+
+    unsafe { (*current).waker() }.expect("coroutine was not spawned on a Scheduler")
+}
+
+/// Tells the JoinHandle, if any, why a finished coroutine has no result
+fn report_failure(state: CoroutineState, join: Option<Arc<dyn JoinSlot>>) {
+    let Some(join) = join else {
+        return;
+    };
+    match state {
+        CoroutineState::Panicked(payload) => join.fail(JoinError::Panicked(payload)),
+        CoroutineState::Cancelled => join.fail(JoinError::Cancelled),
+        _ => {}
+    }
+}
+
+/// Trait for type erasure of coroutines
+/// Allows storing different types of coroutines in the scheduler
+trait AnyCoroutine {
+    fn id(&self) -> usize;
+    fn waker(&self) -> Option<Waker>;
+    fn state(&self) -> &CoroutineState;
+    fn set_state(&mut self, state: CoroutineState);
+    /// Whether cancel() was called on the coroutine's JoinHandle
+    fn cancel_requested(&self) -> bool;
+    /// Switches onto the coroutine's stack until it gives control back
+    unsafe fn resume(&mut self);
+    /// Switches from the coroutine's stack back to the scheduler, leaving
+    /// the coroutine in `state` (Suspended or Blocked)
+    /// Must only be called by the coroutine itself while it is running
+    unsafe fn suspend(&mut self, state: CoroutineState);
+    /// Reports a panic to the JoinHandle, if any, and gives up the stack
+    /// of a finished coroutine so it can be reused (futures have none)
+    fn finish(self: Box<Self>) -> Option<Stack>;
+}
+
+impl<F: FnOnce()> AnyCoroutine for Coroutine<F> {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn waker(&self) -> Option<Waker> {
+        self.waker.clone()
+    }
+
+    fn state(&self) -> &CoroutineState {
+        &self.state
+    }
+
+    fn set_state(&mut self, state: CoroutineState) {
+        self.state = state;
+    }
+
+    fn cancel_requested(&self) -> bool {
+        Coroutine::cancel_requested(self)
+    }
+
+    unsafe fn resume(&mut self) {
+        let this: *mut Self = self;
+
+        // The coroutine's address is only stable once it lives in the
+        // scheduler's Box, so hand it to the trampoline on the first resume
+        if (*this).func.is_some() {
+            (*this).context.set_argument(this as usize);
+        }
+
+        let previous = guard::enter((*this).stack.active(Some((*this).id)));
+        coroutine_switch_context(
+            ptr::addr_of_mut!((*this).caller),
+            ptr::addr_of!((*this).context),
+        );
+        guard::leave(previous);
+    }
+
+    unsafe fn suspend(&mut self, state: CoroutineState) {
+        let this: *mut Self = self;
+        (*this).state = state;
+
+        // Execution continues here when the scheduler resumes us
+        coroutine_switch_context(
+            ptr::addr_of_mut!((*this).context),
+            ptr::addr_of!((*this).caller),
+        );
+    }
+
+    fn finish(self: Box<Self>) -> Option<Stack> {
+        report_failure(self.state, self.join);
+        Some(self.stack)
+    }
+}
+
+// Educational Generator Implementation built on Coroutines
+// This implementation shows how generators are a specialized form of coroutines
+// that yield values back to their caller.
+//
+// Key concepts demonstrated:
+// 1. Generator State Management
+// 2. Value Yielding Mechanism
+// 3. Iterator Pattern Integration
+// 4. Suspension Points
+// 5. Resume with Value
+
+/// Represents the internal state of a generator
+/// This extends the coroutine state with generator-specific states
+#[derive(Debug, Clone)]
+pub enum GeneratorStatus {
+    Ready,          // Initial state, ready to start
+    Yielded,        // Suspended after yielding a value
+    Running,        // Currently executing
+    Complete,       // Finished generating values
+}
+
+/// What a single resume() produced
+/// Yielded values and the final return value may have different types
+#[derive(Debug, Clone, PartialEq)]
+pub enum GeneratorState<Y, Ret> {
+    Yielded(Y),     // The body called yield_ and is suspended
+    Complete(Ret),  // The body returned
+}
+
+/// Generator context that includes value management
+/// Extends the coroutine context to handle values flowing in both directions
+#[repr(C)]
+struct GeneratorContext<Y, R, Ret> {
+    // Inherit coroutine context structure
+    registers: Context,         // Callee-saved registers of the body
+
+    // Generator-specific fields
+    yielded_value: Option<Y>,   // Storage for yielded values
+    resume_value: Option<R>,    // Value passed in by the latest resume()
+    return_value: Option<Ret>,  // What the body returned, once complete
+    panic: Option<Box<dyn Any + Send>>,  // What the body panicked with, for resume() to re-raise
+    cancelled: bool,            // Set on drop; the body unwinds out of yield_
+}
+
+impl<Y, R, Ret> GeneratorContext<Y, R, Ret> {
+    /// Creates a new generator context
+    fn new() -> Self {
+        GeneratorContext {
+            // Initialize coroutine context fields
+            registers: Context::new(),
+            // Initialize generator-specific fields
+            yielded_value: None,
+            resume_value: None,
+            return_value: None,
+            panic: None,
+            cancelled: false,
+        }
+    }
+}
+
+/// The main generator structure
+/// Type parameters:
+/// - Y: Type of values yielded by the generator
+/// - F: The generator function type
+/// - R: Type of values sent in through resume()
+/// - Ret: Type of the value returned when the body finishes
+///
+/// The body runs on the generator's private stack, so its locals survive
+/// between calls to resume() exactly like a suspended coroutine's do.
+///
+/// Those locals may be thread-bound (an Rc, a MutexGuard) without the
+/// compiler seeing them, so a generator stays on the thread it was made on:
+///
+/// ```compile_fail
+/// use coroutine_example::Generator;
+///
+/// let mut generator = Generator::new(|y| {
+///     let shared = std::rc::Rc::new(1);
+///     y.yield_(*shared);
+/// });
+/// generator.next();
+/// std::thread::spawn(move || generator.next());
+/// ```
+pub struct Generator<Y, F, R = (), Ret = ()> {
+    stack: Option<Stack>,                         // Reuse coroutine stack management
+    context: Box<GeneratorContext<Y, R, Ret>>,    // Extended context for generators
+    caller: Box<Context>,                         // Where yield_ switches back to
+    state: GeneratorStatus,                       // Generator-specific state
+    func: Option<F>,                              // The generator function
+    invoke: fn(F, &Yielder<Y, R>, R) -> Ret,      // How to call func with its input
+    _not_send: PhantomData<*mut ()>,              // See above
+}
+
+// Both contexts are boxed because the body keeps pointers to them while it
+// is suspended, and the Generator value itself is free to move in between.
+
+/// Handle passed to the generator body for producing values
+/// Lives on the generator's stack for as long as the body runs
+pub struct Yielder<Y, R = ()> {
+    context: *mut Context,
+    yielded_value: *mut Option<Y>,
+    resume_value: *mut Option<R>,
+    cancelled: *const bool,
+    caller: *const Context,
+}
+
+impl<Y, R> Yielder<Y, R> {
+    /// Hands `value` to the caller of resume() and suspends the body
+    /// Execution continues after this call on the following resume(),
+    /// which also supplies the value returned here
+    ///
+    /// If the generator is dropped instead, this unwinds the body so that
+    /// its locals get dropped too. Don't yield from a Drop impl that runs
+    /// during that unwind: the second unwind would abort the process.
+    pub fn yield_(&self, value: Y) -> R {
+        unsafe {
+            *self.yielded_value = Some(value);
+            coroutine_switch_context(self.context, self.caller);
+            if *self.cancelled {
+                panic::resume_unwind(Box::new(Cancelled));
+            }
+            (*self.resume_value)
+                .take()
+                .expect("generator resumed without an input value")
+        }
+    }
+}
+
+/// Trait representing resumable generators
+/// This trait defines how callers drive a generator: send a value in,
+/// get either a yielded value or the final return value back
+pub trait GeneratorFunc<R> {
+    type Yield;
+    type Return;
+
+    /// Executes the generator function until the next yield point or completion
+    fn resume(&mut self, input: R) -> GeneratorState<Self::Yield, Self::Return>;
+
+    /// Runs this generator to completion, then `next`
+    fn chain_with<G>(self, next: G) -> ChainWith<Self, G, Self::Return>
+    where
+        Self: Sized,
+        R: Clone,
+        G: GeneratorFunc<R, Yield = Self::Yield>,
+    {
+        ChainWith::new(self, next)
+    }
+
+    /// Resumes this generator and `other` together, yielding pairs
+    fn zip_with<G>(self, other: G) -> ZipWith<Self, G>
+    where
+        Self: Sized,
+        R: Clone,
+        G: GeneratorFunc<R>,
+    {
+        ZipWith::new(self, other)
+    }
+
+    /// Sends every value this generator yields into `consumer`
+    fn pipe_into<C>(self, consumer: C) -> PipeInto<Self, C, Self::Return>
+    where
+        Self: Sized,
+        C: GeneratorFunc<Option<Self::Yield>>,
+    {
+        PipeInto::new(self, consumer)
+    }
+
+    /// Transforms every yielded value with `f`
+    fn map_yield<U, F>(self, f: F) -> MapYield<Self, F>
+    where
+        Self: Sized,
+        F: FnMut(Self::Yield) -> U,
+    {
+        MapYield::new(self, f)
+    }
+
+    /// Iterates over the yielded values of a generator that takes no input
+    fn yields(self) -> Yields<Self>
+    where
+        Self: Sized + GeneratorFunc<()>,
+    {
+        Yields::new(self)
+    }
+
+    /// Turns a generator that takes no input into a futures Stream
+    fn into_stream(self) -> IntoStream<Self>
+    where
+        Self: Sized + GeneratorFunc<()>,
+    {
+        IntoStream::new(self)
+    }
+}
+
+impl<Y, F> Generator<Y, F>
+where
+    F: FnOnce(&Yielder<Y>),
+{
+    /// Creates a new generator from a function
+    /// The function receives a Yielder and calls yield_ for every value
+    pub fn new(func: F) -> Self {
+        fn invoke<Y, F: FnOnce(&Yielder<Y>)>(func: F, yielder: &Yielder<Y>, _: ()) {
+            func(yielder)
+        }
+
+        Generator::build(func, invoke::<Y, F>)
+    }
+}
+
+impl<Y, F, R, Ret> Generator<Y, F, R, Ret>
+where
+    F: FnOnce(&Yielder<Y, R>, R) -> Ret,
+{
+    /// Creates a generator that consumes input as it runs
+    /// The body gets the first resume() input as its argument; every later
+    /// input comes back as the return value of yield_
+    pub fn with_input(func: F) -> Self {
+        fn invoke<Y, R, Ret, F: FnOnce(&Yielder<Y, R>, R) -> Ret>(
+            func: F,
+            yielder: &Yielder<Y, R>,
+            input: R,
+        ) -> Ret {
+            func(yielder, input)
+        }
+
+        Generator::build(func, invoke::<Y, R, Ret, F>)
+    }
+}
+
+impl<Y, F, R, Ret> Generator<Y, F, R, Ret> {
+    fn build(func: F, invoke: fn(F, &Yielder<Y, R>, R) -> Ret) -> Self {
+        let stack = pool::generator_pool().acquire(STACK_SIZE);  // Reuse coroutine stacks
+
+        let mut context = Box::new(GeneratorContext::new());
+        unsafe {
+            let entry = generator_entry::<Y, F, R, Ret> as *const () as usize;
+            context.registers.start(stack.top(), entry);
+        }
+
+        Generator {
+            stack: Some(stack),
+            context,
+            caller: Box::new(Context::new()),
+            state: GeneratorStatus::Ready,
+            func: Some(func),
+            invoke,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Sends `input` into the generator and runs it to the next yield point
+    /// This is the main method for interacting with the generator.
+    ///
+    /// Panics if the generator has already completed. A panic in the body
+    /// carries on here, in the caller, and leaves the generator complete.
+    pub fn resume(&mut self, input: R) -> GeneratorState<Y, Ret> {
+        if let GeneratorStatus::Complete = self.state {
+            panic!("generator resumed after completion");
+        }
+
+        // Set state to running
+        self.state = GeneratorStatus::Running;
+        self.context.resume_value = Some(input);
+
+        // Run the body on its own stack until the next yield point
+        unsafe {
+            self.switch_in();
+        }
+
+        if let Some(payload) = self.context.panic.take() {
+            self.state = GeneratorStatus::Complete;
+            panic::resume_unwind(payload);
+        }
+
+        // Every value passes through the context on its way out
+        match self.context.yielded_value.take() {
+            Some(value) => {
+                self.state = GeneratorStatus::Yielded;
+                GeneratorState::Yielded(value)
+            }
+            None => {
+                self.state = GeneratorStatus::Complete;
+                let value = self.context.return_value.take();
+                GeneratorState::Complete(value.expect("generator finished without a return value"))
+            }
+        }
+    }
+
+    /// Whether the body has run to completion
+    pub fn is_complete(&self) -> bool {
+        matches!(self.state, GeneratorStatus::Complete)
+    }
+
+    /// Switches onto the body's stack until it yields or finishes
+    unsafe fn switch_in(&mut self) {
+        if self.func.is_some() {
+            let this = self as *mut Self as usize;
+            self.context.registers.set_argument(this);
+        }
+        let stack = self.stack.as_ref().expect("generator has a stack until dropped");
+        let previous = guard::enter(stack.active(None));
+        coroutine_switch_context(&mut *self.caller, &self.context.registers);
+        guard::leave(previous);
+    }
+}
+
+// Hand the stack back for the next generator to use
+impl<Y, F, R, Ret> Drop for Generator<Y, F, R, Ret> {
+    fn drop(&mut self) {
+        // A body suspended in yield_ still owns its locals. Unwind it, the
+        // way cancellation unwinds a coroutine, so their destructors run
+        // before the stack goes to someone else.
+        if let GeneratorStatus::Yielded = self.state {
+            self.context.cancelled = true;
+            loop {
+                unsafe { self.switch_in() };
+                // A body that caught the unwind and yielded again gets
+                // another one from that yield_
+                if self.context.yielded_value.take().is_none() {
+                    break;
+                }
+            }
+        }
+
+        if let Some(stack) = self.stack.take() {
+            pool::generator_pool().release(stack);
+        }
+    }
+}
+
+impl<Y, F, R, Ret> GeneratorFunc<R> for Generator<Y, F, R, Ret> {
+    type Yield = Y;
+    type Return = Ret;
+
+    fn resume(&mut self, input: R) -> GeneratorState<Y, Ret> {
+        Generator::resume(self, input)
+    }
+}
+
+/// Entry point of every generator body, running on the generator's stack
+/// Stores the body's return value and switches back with nothing yielded,
+/// which resume() reads as completion.
+///
+/// As with coroutine_entry, a panic must not unwind past this frame. It is
+/// caught and handed to resume(), which raises it again on the caller's
+/// stack.
+extern "C" fn generator_entry<Y, F, R, Ret>(gen: *mut Generator<Y, F, R, Ret>) -> ! {
+    unsafe {
+        // Grab everything we need now: after the first yield the Generator
+        // may move, but its boxed contexts stay where they are
+        let func = (*gen).func.take().expect("generator started twice");
+        let invoke = (*gen).invoke;
+        let context: *mut GeneratorContext<Y, R, Ret> = &mut *(*gen).context;
+
+        let yielder = Yielder {
+            context: ptr::addr_of_mut!((*context).registers),
+            yielded_value: ptr::addr_of_mut!((*context).yielded_value),
+            resume_value: ptr::addr_of_mut!((*context).resume_value),
+            cancelled: ptr::addr_of!((*context).cancelled),
+            caller: &*(*gen).caller as *const Context,
+        };
+
+        let input = (*context).resume_value.take().expect("generator started without input");
+        match panic::catch_unwind(AssertUnwindSafe(|| invoke(func, &yielder, input))) {
+            Ok(value) => (*context).return_value = Some(value),
+            // Dropped while suspended: nobody is left to see a result
+            Err(payload) if payload.is::<Cancelled>() => {}
+            Err(payload) => (*context).panic = Some(payload),
+        }
+
+        coroutine_switch_context(yielder.context, yielder.caller);
+    }
+    unreachable!("completed generator was resumed");
+}
+
+/// Implement Iterator for Generator
+/// This allows generators that take no input to be used in for loops
+/// and with Iterator methods
+impl<Y, F, Ret> Iterator for Generator<Y, F, (), Ret> {
+    type Item = Y;
+
+    /// Advances the generator to produce the next value
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.is_complete() {
+            return None;
+        }
+
+        match self.resume(()) {
+            GeneratorState::Yielded(value) => Some(value),
+            GeneratorState::Complete(_) => None,
+        }
+    }
+}
+
+/// Example generator creation helper
+/// Makes it easier to create common types of generators
+pub fn create_range_generator(start: i32, end: i32) -> Generator<i32, impl FnOnce(&Yielder<i32>)> {
+    Generator::new(move |y| {
+        for value in start..end {
+            y.yield_(value);
+        }
+    })
+}
+
+/// Yields the Fibonacci numbers, stopping before they overflow a u64
+pub fn create_fibonacci_generator() -> Generator<u64, impl FnOnce(&Yielder<u64>)> {
+    Generator::new(|y| {
+        let (mut current, mut next) = (0u64, 1u64);
+        loop {
+            y.yield_(current);
+            let Some(after) = current.checked_add(next) else {
+                return;
+            };
+            (current, next) = (next, after);
+        }
+    })
+}
+
+/// Yields the lines of a file, one read at a time
+/// Only the current line is in memory. The generator returns the first
+/// read error, if any, which ends it early.
+pub fn create_line_reader(
+    path: impl AsRef<std::path::Path>,
+) -> std::io::Result<impl GeneratorFunc<(), Yield = String, Return = std::io::Result<()>>> {
+    use std::io::BufRead;
+
+    let reader = std::io::BufReader::new(std::fs::File::open(path)?);
+    Ok(Generator::with_input(move |y, ()| {
+        for line in reader.lines() {
+            y.yield_(line?);
+        }
+        Ok(())
+    }))
+}
+
+/// What create_tokenizer() splits its input into
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Number(i64),
+    Word(String),    // Letters, digits and underscores, not all digits
+    Symbol(char),    // Any other character except whitespace
+}
+
+/// A tokenizer fed one character per resume()
+/// Send Some(c) for every character and None once the input is over.
+/// Every resume() yields at most one token, None meaning "need more
+/// input"; a character that ends a word and is a symbol itself produces
+/// two, so the backlog comes out on later resumes. After the end of the
+/// input, keep sending None until the generator returns how many tokens
+/// it produced.
+pub fn create_tokenizer() -> impl GeneratorFunc<Option<char>, Yield = Option<Token>, Return = usize> {
+    // Turns the characters collected so far into a token, if there are any
+    fn finish_word(word: &mut String, ready: &mut VecDeque<Token>) {
+        if word.is_empty() {
+            return;
+        }
+        let word = mem::take(word);
+        ready.push_back(match word.parse() {
+            Ok(number) => Token::Number(number),
+            Err(_) => Token::Word(word),
+        });
+    }
+
+    Generator::with_input(|y, first: Option<char>| {
+        let mut ready = VecDeque::new();
+        let mut word = String::new();
+        let mut produced = 0;
+        let mut input = first;
+
+        while let Some(c) = input {
+            if c.is_alphanumeric() || c == '_' {
+                word.push(c);
+            } else {
+                finish_word(&mut word, &mut ready);
+                if !c.is_whitespace() {
+                    ready.push_back(Token::Symbol(c));
+                }
+            }
+
+            let token = ready.pop_front();
+            produced += usize::from(token.is_some());
+            input = y.yield_(token);
+        }
+
+        // End of input: hand out whatever is left, one token at a time
+        finish_word(&mut word, &mut ready);
+        while let Some(token) = ready.pop_front() {
+            produced += 1;
+            y.yield_(Some(token));
+        }
+        produced
+    })
+}
+
+
+/// Educational demonstrations showing various coroutine concepts
+pub mod demos {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Demonstrates basic coroutine creation and execution
+    pub fn demo_basic_usage() {
+        println!("Demo: Basic Coroutine Usage");
+        
+        let mut scheduler = Scheduler::new();
+        let counter = Cell::new(0);
+        
+        // The scope waits for the coroutine, so it may borrow `counter`
+        scheduler.scope(|s| {
+            s.spawn(|| {
+                println!("Coroutine executing...");
+                counter.set(counter.get() + 1);
+                println!("Coroutine complete!");
+            });
+        });
+        
+        assert_eq!(counter.get(), 1);
+    }
+
+    /// Shows multiple coroutines interacting
+    pub fn demo_multiple_coroutines() {
+        println!("Demo: Multiple Coroutines");
+        
+        let mut scheduler = Scheduler::new();
+        let started = Instant::now();
+
+        // Spawn several coroutines that each produce a result
+        let workers: Vec<JoinHandle<usize>> = (0..3)
+            .map(|id| {
+                scheduler.spawn(move || {
+                    println!("Coroutine {} starting", id);
+                    yield_now();
+                    // Simulate some work; the other coroutines sleep at the same time
+                    sleep(Duration::from_millis(100));
+                    println!("Coroutine {} complete", id);
+                    id * 10
+                })
+            })
+            .collect();
+
+        // A coordinator coroutine that waits on the others
+        let total = scheduler.spawn(move || {
+            let sum: usize = workers.into_iter().map(|w| w.join().unwrap()).sum();
+            println!("Coordinator collected {}", sum);
+            sum
+        });
+
+        scheduler.run();
+        println!("Three 100ms sleeps took {:?} in total", started.elapsed());
+        assert_eq!(total.join().unwrap(), 30);
+    }
+
+    /// Demonstrates coroutines talking through channels
+    pub fn demo_channels() {
+        println!("Demo: Coroutine Channels");
+
+        let mut scheduler = Scheduler::new();
+
+        // A bounded channel: the producer blocks whenever two items are queued
+        let (tx, rx) = bounded(2);
+        let producer = scheduler.spawn(move || {
+            for item in 0..5 {
+                println!("Producer sending {}", item);
+                tx.send(item).unwrap();
+            }
+            println!("Producer done");
+        });
+        let consumer = scheduler.spawn(move || {
+            let mut received = Vec::new();
+            for item in rx {
+                println!("Consumer received {}", item);
+                received.push(item);
+            }
+            received
+        });
+
+        // An unbounded channel fanning in from several producers
+        let (tx, rx) = unbounded();
+        for id in 0..3 {
+            let tx = tx.clone();
+            scheduler.spawn(move || tx.send(id * 100).unwrap());
+        }
+        drop(tx);
+        let collector = scheduler.spawn(move || rx.sum::<usize>());
+
+        scheduler.run();
+
+        producer.join().unwrap();
+        assert_eq!(consumer.join().unwrap(), [0, 1, 2, 3, 4]);
+        assert_eq!(collector.join().unwrap(), 300);
+        println!("Fan-in total: 300");
+    }
+
+    /// Demonstrates coroutines spread over several OS threads
+    pub fn demo_multi_threaded() {
+        println!("Demo: M:N Scheduling");
+
+        let mut scheduler = MultiScheduler::new(4);
+        let threads_seen = Arc::new(Mutex::new(HashSet::new()));
+
+        // Everything starts on worker 0; the other workers have to steal.
+        // SAFETY (all spawns below): the bodies only keep numbers, handles
+        // and channel ends across suspension points, all fine on any thread
+        let workers: Vec<JoinHandle<u64>> = (0..16u64)
+            .map(|id| {
+                let threads_seen = threads_seen.clone();
+                let options = SpawnOptions::new().stack_size(64 * 1024);
+                unsafe {
+                    scheduler.spawn_on(0, options, move || {
+                        let mut sum = 0;
+                        for round in 0..5 {
+                            // Some CPU work, then give the worker back
+                            sum += (0..20_000u64).map(|n| n * id % 7 + round).sum::<u64>();
+                            threads_seen.lock().unwrap().insert(thread::current().id());
+                            yield_now();
+                        }
+                        sleep(Duration::from_millis(5));
+                        sum
+                    })
+                }
+            })
+            .collect();
+
+        // Channels and joins work across threads too
+        let (tx, rx) = bounded(1);
+        let total = unsafe {
+            scheduler.spawn(move || {
+                let sum: u64 = workers.into_iter().map(|w| w.join().unwrap()).sum();
+                tx.send(sum).unwrap();
+            })
+        };
+        let reporter = unsafe { scheduler.spawn(move || rx.recv().unwrap()) };
+
+        scheduler.run();
+
+        total.join().unwrap();
+        let sum = reporter.join().unwrap();
+        let expected: u64 = (0..16u64)
+            .map(|id| (0..5).map(|round| (0..20_000u64).map(|n| n * id % 7 + round).sum::<u64>()).sum::<u64>())
+            .sum();
+        assert_eq!(sum, expected);
+
+        println!(
+            "16 coroutines ran on {} of {} threads with {} steals",
+            threads_seen.lock().unwrap().len(),
+            scheduler.threads(),
+            scheduler.steals()
+        );
+    }
+
+    /// Demonstrates running the same coroutines under different policies
+    pub fn demo_scheduling_policies() {
+        println!("Demo: Scheduling Policies");
+
+        // Three coroutines taking two turns each; the log shows who ran when
+        fn turns_under(policy: impl SchedulingPolicy + 'static) -> Vec<String> {
+            let mut scheduler = Scheduler::builder().policy(policy).build();
+            let log = RefCell::new(Vec::new());
+            let now = Instant::now();
+
+            scheduler.scope(|s| {
+                for (name, priority, deadline_ms) in [("low", 0, 30), ("high", 10, 20), ("mid", 5, 10)] {
+                    let log = &log;
+                    let options = SpawnOptions::new()
+                        .priority(priority)
+                        .deadline(now + Duration::from_millis(deadline_ms));
+                    s.spawn_with(options, move || {
+                        for turn in 1..=2 {
+                            log.borrow_mut().push(format!("{}{}", name, turn));
+                            yield_now();
+                        }
+                    });
+                }
+            });
+
+            log.into_inner()
+        }
+
+        let round_robin = turns_under(RoundRobin::new());
+        println!("Round-robin:     {:?}", round_robin);
+        assert_eq!(round_robin, ["low1", "high1", "mid1", "low2", "high2", "mid2"]);
+
+        let priority = turns_under(PriorityQueue::new());
+        println!("Priority:        {:?}", priority);
+        assert_eq!(priority, ["high1", "high2", "mid1", "mid2", "low1", "low2"]);
+
+        let deadline = turns_under(EarliestDeadlineFirst::new());
+        println!("Earliest first:  {:?}", deadline);
+        assert_eq!(deadline, ["mid1", "mid2", "high1", "high2", "low1", "low2"]);
+
+        // Fair share: two busy coroutines with weights 1 and 3 split 40 slices
+        let mut scheduler = Scheduler::builder().policy(FairShare::new()).build();
+        let slices = Cell::new(0);
+        let shares: Vec<JoinHandle<usize>> = scheduler.scope(|s| {
+            [1, 3]
+                .into_iter()
+                .map(|weight| {
+                    let slices = &slices;
+                    s.spawn_with(SpawnOptions::new().weight(weight), move || {
+                        let mut mine = 0;
+                        while slices.get() < 40 {
+                            let start = Instant::now();
+                            while start.elapsed() < Duration::from_micros(200) {}
+                            slices.set(slices.get() + 1);
+                            mine += 1;
+                            yield_now();
+                        }
+                        mine
+                    })
+                })
+                .collect()
+        });
+
+        let shares: Vec<usize> = shares.into_iter().map(|share| share.join().unwrap()).collect();
+        println!("Fair share:      weight 1 ran {} slices, weight 3 ran {}", shares[0], shares[1]);
+        assert!(shares[1] >= 2 * shares[0]);
+    }
+
+    /// Demonstrates futures and coroutines sharing one scheduler
+    pub fn demo_async_bridge() {
+        println!("Demo: Async/Await Bridge");
+
+        let mut scheduler = Scheduler::new();
+
+        // A stackful coroutine that sleeps like any other
+        let stackful = scheduler.spawn(|| {
+            sleep(Duration::from_millis(20));
+            println!("Coroutine woke up");
+            20
+        });
+
+        // A stackless future awaiting it
+        let stackless = scheduler.spawn_future(async move {
+            let value = stackful.await.unwrap();
+            println!("Future got {} from the coroutine", value);
+            value + 1
+        });
+
+        // And the other way around: a coroutine joining the future
+        let joiner = scheduler.spawn(move || stackless.join().unwrap() * 2);
+
+        // Futures don't take a stack from the pool
+        let before = scheduler.pool_stats();
+        let futures: Vec<JoinHandle<usize>> = (0..1000).map(|n| scheduler.spawn_future(async move { n })).collect();
+        assert_eq!(scheduler.pool_stats(), before);
+
+        // Unlike spawned work, the future given to block_on may borrow
+        let offset = 100;
+        let total = scheduler.block_on(async {
+            let mut sum = 0;
+            for future in futures {
+                sum += future.await.unwrap();
+            }
+            sum + joiner.await.unwrap() + offset
+        });
+
+        println!("1000 futures and 2 coroutines produced {}", total);
+        assert_eq!(total, 499_500 + 42 + 100);
+    }
+
+    /// Demonstrates an echo server made of thousands of coroutines
+    pub fn demo_echo_server() {
+        use std::io::{Read, Write};
+        use std::net::Shutdown;
+
+        println!("Demo: Echo Server");
+
+        const CLIENTS: usize = 2000;
+
+        // Both ends of every connection live in this process
+        let sockets = raise_fd_limit(2 * CLIENTS as u64 + 64);
+        let clients = CLIENTS.min((sockets.saturating_sub(64) / 2) as usize);
+
+        let mut scheduler = Scheduler::builder().stack_size(64 * 1024).build();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let started = Instant::now();
+
+        // One acceptor hands each connection to a handler coroutine
+        let (tx, rx) = unbounded();
+        scheduler.spawn(move || {
+            for _ in 0..clients {
+                tx.send(listener.accept().unwrap().0).unwrap();
+            }
+        });
+        let rx = Rc::new(rx);
+        for _ in 0..clients {
+            let rx = rx.clone();
+            scheduler.spawn(move || {
+                let mut stream = rx.recv().unwrap();
+                let mut buf = [0u8; 256];
+                loop {
+                    match stream.read(&mut buf).unwrap() {
+                        0 => break,
+                        n => stream.write_all(&buf[..n]).unwrap(),
+                    }
+                }
+            });
+        }
+
+        // And one coroutine per client
+        let replies: Vec<JoinHandle<bool>> = (0..clients)
+            .map(|id| {
+                scheduler.spawn(move || {
+                    let mut stream = TcpStream::connect(addr).unwrap();
+                    let message = format!("hello from client {}", id);
+                    stream.write_all(message.as_bytes()).unwrap();
+                    stream.shutdown(Shutdown::Write).unwrap();
+
+                    let mut reply = String::new();
+                    stream.read_to_string(&mut reply).unwrap();
+                    reply == message
+                })
+            })
+            .collect();
+
+        scheduler.run();
+
+        let echoed = replies.into_iter().filter_map(|reply| reply.join().ok()).filter(|&ok| ok).count();
+        assert_eq!(echoed, clients);
+        println!(
+            "{} coroutines served {} clients on one thread in {:?}",
+            2 * clients + 1,
+            clients,
+            started.elapsed()
+        );
+    }
+
+    /// Raises the open file limit towards `wanted` and returns the new limit
+    fn raise_fd_limit(wanted: u64) -> u64 {
+        let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+        unsafe {
+            libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit);
+            if limit.rlim_cur < wanted {
+                limit.rlim_cur = wanted.min(limit.rlim_max);
+                libc::setrlimit(libc::RLIMIT_NOFILE, &limit);
+            }
+        }
+        limit.rlim_cur
+    }
+
+    /// Demonstrates per-coroutine statistics and a trace of the scheduler
+    pub fn demo_tracing() {
+        println!("Demo: Tracing");
+
+        let mut scheduler = Scheduler::builder().tracing(true).build();
+
+        // A yielder, a sleeper and a pair talking through a channel
+        let yielder = scheduler.spawn(|| {
+            for _ in 0..3 {
+                yield_now();
+            }
+        });
+        let sleeper = scheduler.spawn(|| sleep(Duration::from_millis(10)));
+        let (tx, rx) = bounded(1);
+        let producer = scheduler.spawn(move || {
+            for item in 0..3 {
+                tx.send(item).unwrap();
+            }
+        });
+        let consumer = scheduler.spawn(move || rx.sum::<i32>());
+
+        let ids = [yielder.id(), sleeper.id(), producer.id(), consumer.id()];
+        scheduler.run();
+        assert_eq!(consumer.join().unwrap(), 3);
+
+        println!("{:>4} {:>8} {:>12} {:>12} {:>12}", "id", "resumes", "running", "suspended", "blocked");
+        let stats = scheduler.coroutine_stats();
+        for stats in &stats {
+            println!(
+                "{:>4} {:>8} {:>12?} {:>12?} {:>12?}",
+                stats.id, stats.resumes, stats.run_time, stats.suspended_time, stats.blocked_time
+            );
+        }
+
+        // Three yields mean four slices; the sleeper mostly waited
+        assert_eq!(stats.iter().map(|stats| stats.id).collect::<Vec<_>>(), ids);
+        assert_eq!(stats[0].resumes, 4);
+        assert!(stats[1].blocked_time >= Duration::from_millis(10));
+        assert!(stats.iter().all(|stats| stats.finished_at.is_some()));
+
+        let path = std::env::temp_dir().join("coroutine_trace.json");
+        scheduler
+            .write_chrome_trace(std::fs::File::create(&path).unwrap())
+            .unwrap();
+        println!("Timeline written to {} (open it in chrome://tracing or ui.perfetto.dev)", path.display());
+    }
+
+    /// Demonstrates scoped coroutines borrowing from the caller's stack
+    pub fn demo_scoped_coroutines() {
+        println!("Demo: Structured Concurrency with Scopes");
+
+        let mut scheduler = Scheduler::new();
+        let mut numbers: Vec<u32> = (1..=8).collect();
+        let total = Cell::new(0);
+
+        scheduler.scope(|s| {
+            // Each coroutine gets a mutable slice of the caller's vector
+            for (index, chunk) in numbers.chunks_mut(4).enumerate() {
+                s.spawn(move || {
+                    for number in chunk.iter_mut() {
+                        *number *= 10;
+                        yield_now();
+                    }
+                    println!("Chunk {} scaled", index);
+                });
+            }
+
+            // Children can spawn siblings; the scope waits for those too
+            s.spawn(|| {
+                sleep(Duration::from_millis(10));
+                s.spawn(|| total.set(total.get() + 1));
+            });
+        });
+
+        // Every borrow has ended, so the vector is ours again
+        println!("Scaled: {:?}", numbers);
+        assert_eq!(numbers, [10, 20, 30, 40, 50, 60, 70, 80]);
+        assert_eq!(total.get(), 1);
+
+        // A panicking scope body cancels the children before unwinding further
+        let resource = RefCell::new(String::from("borrowed"));
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+            scheduler.scope(|s| {
+                s.spawn(|| {
+                    let _borrow = resource.borrow_mut();
+                    sleep(Duration::from_secs(60));
+                });
+                panic!("scope body failed");
+            })
+        }));
+        assert!(outcome.is_err());
+        // The child released its borrow while unwinding
+        println!("After the failed scope: {}", resource.borrow());
+    }
+
+    /// Demonstrates that one panicking coroutine doesn't take down the rest
+    pub fn demo_panic_isolation() {
+        println!("Demo: Panic Isolation");
+
+        let mut scheduler = Scheduler::new();
+
+        let before = scheduler.spawn(|| {
+            yield_now();
+            "ran before the panic"
+        });
+        let faulty = scheduler.spawn(|| -> &'static str {
+            yield_now();
+            panic!("something went wrong inside the coroutine");
+        });
+        let after = scheduler.spawn(|| {
+            yield_now();
+            "ran after the panic"
+        });
+
+        scheduler.run();
+
+        println!("First coroutine: {:?}", before.join());
+        let error = faulty.join().unwrap_err();
+        println!("Faulty coroutine: {}", error);
+        println!("Last coroutine: {:?}", after.join());
+        assert!(error.to_string().contains("something went wrong"));
+    }
+
+    /// Demonstrates stack usage patterns
+    pub fn demo_stack_usage() {
+        println!("Demo: Stack Usage Patterns");
+        
+        let mut scheduler = Scheduler::new();
+        
+        scheduler.spawn(|| {
+            // Recursive function to demonstrate stack growth
+            fn recursive(depth: usize) -> usize {
+                if depth == 0 {
+                    return 0;
+                }
+                let buffer = [0u8; 1024]; // Local array to use stack space
+                recursive(depth - 1) + buffer[0] as usize
+            }
+            
+            println!("Starting deep recursion...");
+            recursive(10);
+            println!("Recursion complete!");
+        });
+        
+        scheduler.run();
+    }
+
+    /// Demonstrates guard pages catching a runaway recursion
+    /// The overflow aborts the process, so it runs in a child process
+    /// (this same binary started with --overflow) and we show its report
+    pub fn demo_stack_overflow_detection() {
+        println!("Demo: Stack Overflow Detection");
+
+        let exe = std::env::current_exe().expect("cannot locate own executable");
+        let output = std::process::Command::new(exe)
+            .arg("--overflow")
+            .output()
+            .expect("failed to run overflow child");
+
+        let report = String::from_utf8_lossy(&output.stderr);
+        println!("Child exited with {}, reporting:{}", output.status, report.trim_end());
+        assert!(report.contains("overflowed its stack"));
+    }
+
+    /// Recurses far past the end of a 2MB coroutine stack
+    /// Only ever run in the child process started by the demo above
+    pub fn overflow_stack() {
+        let mut scheduler = Scheduler::new();
+
+        scheduler.spawn(|| {
+            fn recursive(depth: usize) -> usize {
+                if depth == 0 {
+                    return 0;
+                }
+                let buffer = [depth as u8; 1024];
+                recursive(depth - 1) + std::hint::black_box(buffer)[0] as usize
+            }
+
+            recursive(1_000_000);
+        });
+
+        scheduler.run();
+    }
+
+    /// Demonstrates configurable stack sizes and stack recycling
+    pub fn demo_stack_pool() {
+        println!("Demo: Stack Sizes and Pooling");
+
+        let mut scheduler = Scheduler::builder()
+            .stack_size(64 * 1024)
+            .pool_capacity(256)
+            .build();
+        let finished = Cell::new(0);
+
+        // Two waves: the second one runs entirely on recycled stacks
+        for wave in 0..2 {
+            scheduler.scope(|s| {
+                for _ in 0..200 {
+                    s.spawn(|| finished.set(finished.get() + 1));
+                }
+            });
+
+            let stats = scheduler.pool_stats();
+            println!("After wave {}: {} hits, {} misses, {} cached",
+                wave, stats.hits, stats.misses, stats.cached);
+        }
+
+        // One coroutine that needs more room than the 64KB default
+        scheduler.spawn_with(SpawnOptions::new().stack_size(4 * 1024 * 1024), || {
+            let buffer = [1u8; 1024 * 1024];
+            println!("Big-stack coroutine summed {} bytes",
+                buffer.iter().map(|&b| b as usize).sum::<usize>());
+        });
+        scheduler.run();
+
+        let stats = scheduler.pool_stats();
+        assert_eq!(finished.get(), 400);
+        assert_eq!((stats.hits, stats.misses), (200, 201));
+    }
+
+    /// Demonstrates memory and resource management patterns
+    pub fn demo_resource_management() {
+        println!("Demo: Resource Management and RAII in Coroutines");
+        
+        // Simulate a resource that needs cleanup
+        struct ManagedResource {
+            id: usize,
+        }
+        
+        impl ManagedResource {
+            fn new(id: usize) -> Self {
+                println!("Resource {} allocated", id);
+                ManagedResource { id }
+            }
+        }
+        
+        impl Drop for ManagedResource {
+            fn drop(&mut self) {
+                println!("Resource {} cleaned up", self.id);
+            }
+        }
+        
+        let mut scheduler = Scheduler::new();
+        scheduler.spawn(|| {
+            // Create resources with different lifetimes
+            let _resource1 = ManagedResource::new(1);
+            {
+                let _resource2 = ManagedResource::new(2);
+                // resource2 will be cleaned up here
+            }
+            // resource1 will be cleaned up when coroutine ends
+        });
+        
+        scheduler.run();
+
+        // Cancellation unwinds the coroutine's stack at its next suspension
+        // point, so everything it holds is cleaned up like on a normal return
+        let mut scheduler = Scheduler::new();
+        let (_tx, rx) = unbounded::<()>();
+        let worker = scheduler.spawn(move || {
+            let _resource3 = ManagedResource::new(3);
+            let _resource4 = ManagedResource::new(4);
+            // Nobody ever sends: only cancellation gets us out of here
+            rx.recv().ok();
+            unreachable!("the receiver was cancelled");
+        });
+        let sleeper = scheduler.spawn(|| {
+            let _resource5 = ManagedResource::new(5);
+            sleep(Duration::from_secs(60));
+        });
+
+        let started = Instant::now();
+        scheduler.spawn(move || {
+            yield_now();
+            println!("Cancelling the blocked and the sleeping coroutine");
+            worker.cancel();
+            sleeper.cancel();
+            assert!(matches!(worker.join(), Err(JoinError::Cancelled)));
+            assert!(matches!(sleeper.join(), Err(JoinError::Cancelled)));
+        });
+
+        scheduler.run();
+        // The sleeper's timer went away with it
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    /// Demonstrates suspendable operations and resumption
+    pub fn demo_suspension_points() {
+        println!("Demo: Suspension Points and Resumption");
+        
+        struct SuspendableOperation {
+            steps: Vec<usize>,
+            current_step: usize,
+        }
+        
+        impl SuspendableOperation {
+            fn new(steps: Vec<usize>) -> Self {
+                SuspendableOperation {
+                    steps,
+                    current_step: 0,
+                }
+            }
+            
+            fn resume(&mut self) -> Option<usize> {
+                if self.current_step < self.steps.len() {
+                    let step = self.steps[self.current_step];
+                    self.current_step += 1;
+                    Some(step)
+                } else {
+                    None
+                }
+            }
+        }
+        
+        let mut scheduler = Scheduler::new();
+        let log = RefCell::new(Vec::new());
+
+        // Two operations that yield after every step, so their steps interleave
+        scheduler.scope(|s| {
+            for name in ["A", "B"] {
+                let log = &log;
+                let mut operation = SuspendableOperation::new(vec![1, 2, 3]);
+
+                s.spawn(move || {
+                    while let Some(step) = operation.resume() {
+                        println!("Operation {} executing step {}", name, step);
+                        log.borrow_mut().push(format!("{}{}", name, step));
+                        sleep(Duration::from_millis(100));
+                        // Suspend here; the other operation runs before we continue
+                        yield_now();
+                    }
+                });
+            }
+        });
+
+        assert_eq!(log.into_inner(), ["A1", "B1", "A2", "B2", "A3", "B3"]);
+    }
+
+    /// Demonstrates basic generator usage
+    pub fn demo_basic_generator() {
+        println!("Demo: Basic Generator Usage");
+        
+        // Create a simple range generator
+        let mut gen = create_range_generator(0, 5);
+        
+        // Manual iteration (deliberately not a for loop)
+        #[allow(clippy::while_let_on_iterator)]
+        while let Some(value) = gen.next() {
+            println!("Generated value: {}", value);
+        }
+    }
+
+    /// Demonstrates using generators with the Iterator trait
+    pub fn demo_generator_iterator() {
+        println!("Demo: Generator as Iterator");
+        
+        let gen = create_range_generator(0, 5);
+        
+        // Use iterator methods
+        let sum: i32 = gen.sum();
+        println!("Sum of generated values: {}", sum);
+    }
+
+    /// Demonstrates sending values into a generator with resume()
+    pub fn demo_resume_with_value() {
+        println!("Demo: Resume with Value");
+
+        // Consumes samples, yields the running average and finally
+        // returns how many samples it saw
+        let mut averager = Generator::with_input(|y, first: Option<f64>| {
+            let mut total = 0.0;
+            let mut count = 0;
+            let mut input = first;
+
+            while let Some(sample) = input {
+                total += sample;
+                count += 1;
+                input = y.yield_(total / count as f64);
+            }
+            count
+        });
+
+        for sample in [4.0, 8.0, 6.0] {
+            if let GeneratorState::Yielded(average) = averager.resume(Some(sample)) {
+                println!("Sent {}, running average: {}", sample, average);
+            }
+        }
+
+        let result = averager.resume(None);
+        println!("Generator finished: {:?}", result);
+        assert_eq!(result, GeneratorState::Complete(3));
+    }
+
+    /// Demonstrates a more complex generator with state
+    pub fn demo_stateful_generator() {
+        println!("Demo: Stateful Generator");
+        
+        // Create a Fibonacci generator
+        // The state is just two locals kept alive on the generator's stack
+        let mut fib = Generator::new(|y| {
+            let mut prev = 0;
+            let mut curr = 1;
+
+            loop {
+                let next = prev + curr;
+                prev = curr;
+                curr = next;
+                y.yield_(next);
+            }
+        });
+        
+        // Generate first 10 Fibonacci numbers
+        for _ in 0..10 {
+            if let Some(value) = fib.next() {
+                println!("Fibonacci number: {}", value);
+            }
+        }
+    }
+
+    /// Demonstrates composing generators
+    pub fn demo_generator_combinators() {
+        use futures_core::Stream;
+        use std::future::poll_fn;
+        use std::pin::Pin;
+
+        println!("Demo: Generator Combinators");
+
+        // One range after the other; the result carries both return values
+        let mut chained = create_range_generator(0, 3).chain_with(create_range_generator(10, 13));
+        let mut values = Vec::new();
+        while let GeneratorState::Yielded(value) = chained.resume(()) {
+            values.push(value);
+        }
+        println!("Chained: {:?}", values);
+        assert_eq!(values, [0, 1, 2, 10, 11, 12]);
+
+        // Lockstep: stops with the shorter one, reporting the other's last value
+        let mut zipped = create_range_generator(1, 4).zip_with(create_fibonacci_generator());
+        let mut pairs = Vec::new();
+        let last = loop {
+            match zipped.resume(()) {
+                GeneratorState::Yielded(pair) => pairs.push(pair),
+                GeneratorState::Complete(last) => break last,
+            }
+        };
+        println!("Zipped: {:?}, then {:?}", pairs, last);
+        assert_eq!(pairs, [(1, 0), (2, 1), (3, 1)]);
+        assert_eq!(last, (GeneratorState::Complete(()), GeneratorState::Yielded(2)));
+
+        // Characters piped into the tokenizer, which only yields now and then
+        let source = "let total = price * 12;";
+        let characters = Generator::new(|y| {
+            for c in source.chars() {
+                y.yield_(c);
+            }
+        });
+        let tokens: Vec<Token> = characters.pipe_into(create_tokenizer()).yields().flatten().collect();
+        println!("Tokens: {:?}", tokens);
+        assert_eq!(tokens, [
+            Token::Word("let".into()),
+            Token::Word("total".into()),
+            Token::Symbol('='),
+            Token::Word("price".into()),
+            Token::Symbol('*'),
+            Token::Number(12),
+            Token::Symbol(';'),
+        ]);
+
+        // A file, one line per resume, numbered on the way out
+        let path = std::env::temp_dir().join("coroutine_lines.txt");
+        std::fs::write(&path, "first\nsecond\nthird\n").unwrap();
+        let mut line_number = 0;
+        let mut lines = create_line_reader(&path).unwrap().map_yield(|line| {
+            line_number += 1;
+            format!("{}: {}", line_number, line)
+        });
+        while let GeneratorState::Yielded(line) = lines.resume(()) {
+            println!("{}", line);
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(line_number, 3);
+
+        // The Fibonacci numbers as a Stream, summed by async code
+        let mut scheduler = Scheduler::new();
+        let mut fibonacci = create_fibonacci_generator().into_stream();
+        let sum = scheduler.block_on(async move {
+            let mut sum = 0;
+            while let Some(value) = poll_fn(|cx| Pin::new(&mut fibonacci).poll_next(cx)).await {
+                if value > 100 {
+                    break;
+                }
+                sum += value;
+            }
+            sum
+        });
+        println!("Sum of the Fibonacci numbers up to 100, from a stream: {}", sum);
+        assert_eq!(sum, 232);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_panicking_generator_panics_in_the_caller() {
+        let mut generator = Generator::new(|y| {
+            y.yield_(1);
+            panic!("generator body failed");
+        });
+
+        assert_eq!(generator.resume(()), GeneratorState::Yielded(1));
+        let payload = panic::catch_unwind(AssertUnwindSafe(|| generator.resume(()))).unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"generator body failed"));
+        assert!(generator.is_complete());
+
+        // The stack went back to the pool in one piece
+        assert_eq!(create_range_generator(0, 3).sum::<i32>(), 3);
+    }
+
+    #[test]
+    fn dropping_a_suspended_generator_drops_its_locals() {
+        struct SetOnDrop<'a>(&'a Cell<bool>);
+
+        impl Drop for SetOnDrop<'_> {
+            fn drop(&mut self) {
+                self.0.set(true);
+            }
+        }
+
+        let dropped = Cell::new(false);
+        let mut generator = Generator::new(|y| {
+            let _local = SetOnDrop(&dropped);
+            loop {
+                y.yield_(());
+            }
+        });
+
+        assert_eq!(generator.resume(()), GeneratorState::Yielded(()));
+        assert!(!dropped.get());
+        drop(generator);
+        assert!(dropped.get());
+    }
+}
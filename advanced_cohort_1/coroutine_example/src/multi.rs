@@ -0,0 +1,462 @@
+//! M:N scheduling
+//! Scheduler runs every coroutine on the thread that calls run(). This one
+//! spreads M coroutines over N OS threads instead:
+//! 1. Every worker thread owns a ready queue and runs coroutines from it
+//! 2. A worker whose queue runs dry steals from the front of a peer's queue,
+//!    so a suspended coroutine may resume on a different thread than the one
+//!    it yielded on
+//! 3. Blocked coroutines sit in one shared parking lot; whichever worker
+//!    sees the wakeup puts them back on its own queue
+//! 4. A worker with nothing to run or steal sleeps on the wakeup eventfd and
+//!    on its own one, which peers signal when they have queued work for it
+//!    to steal; its next timer bounds the sleep
+//!
+//! Moving a suspended coroutine moves its whole stack to another thread,
+//! and the compiler cannot see that happen. Spawn therefore asks for Send
+//! closures and results, and is unsafe: coroutine bodies must not keep
+//! thread-bound values (Rc, MutexGuard, references into thread-locals)
+//! alive across yield_now(), park(), sleep() or a blocking channel
+//! operation, and only the caller can vouch for that.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::mem;
+use std::rc::Rc;
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{self, AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::timer::{RemoteRemovals, TimerWheel};
+use super::{
+    prepare_coroutine, switch_into, AnyCoroutine, CoroutineState, EventFd, JoinHandle, PoolStats,
+    SpawnOptions, StackPool, WakeQueue, STACK_SIZE, TIMERS,
+};
+
+/// A coroutine that may be handed to another thread
+struct SendCoroutine(Box<dyn AnyCoroutine>);
+
+// SAFETY: MultiScheduler only accepts Send closures with Send results, the
+// join slot is a Mutex around that result, and Stack, Context and Waker are
+// fine to move. What's left is whatever the body keeps on its own stack
+// across a suspension point, which the callers of spawn() vouch for.
+unsafe impl Send for SendCoroutine {}
+
+/// Coroutines waiting for a Waker, shared by all workers
+/// One lock covers both sets, so a wakeup can never slip in between a
+/// worker checking woken_early and parking the coroutine.
+#[derive(Default)]
+struct Parking {
+    blocked: HashMap<usize, SendCoroutine>,  // Parked until woken, by id
+    woken_early: HashSet<usize>,             // Woken before they actually blocked
+}
+
+/// Everything the worker threads share
+struct Shared {
+    queues: Vec<Mutex<VecDeque<SendCoroutine>>>,  // One ready queue per worker
+    parking: Mutex<Parking>,
+    wakeups: Arc<WakeQueue>,
+    live: AtomicUsize,    // Spawned and not finished yet
+    steals: AtomicUsize,  // Coroutines taken from a peer's queue
+    stacks: StackPool,
+    timer_removals: Arc<RemoteRemovals>,  // Timers disarmed on another worker than their own
+    bells: Arc<[EventFd]>,                // One per worker, interrupts its idle sleep
+    sleeping: Vec<AtomicBool>,            // Which workers are in idle()
+}
+
+/// Runs coroutines on a fixed number of OS threads
+/// Teaching points:
+/// - Per-thread run queues keep workers from fighting over one lock
+/// - Work stealing balances load without a central dispatcher
+/// - Stackful coroutines can migrate between threads while suspended
+pub struct MultiScheduler {
+    shared: Arc<Shared>,
+    next_queue: usize,          // Round-robin target for spawn()
+    default_stack_size: usize,
+}
+
+impl MultiScheduler {
+    /// Creates a scheduler with `threads` worker threads
+    ///
+    /// Panics if `threads` is 0.
+    pub fn new(threads: usize) -> Self {
+        assert!(threads > 0, "a multi-threaded scheduler needs at least one thread");
+
+        let bells: Arc<[EventFd]> = (0..threads).map(|_| EventFd::default()).collect();
+        let ring = bells.clone();
+        MultiScheduler {
+            shared: Arc::new(Shared {
+                queues: (0..threads).map(|_| Mutex::new(VecDeque::new())).collect(),
+                parking: Mutex::new(Parking::default()),
+                wakeups: Arc::new(WakeQueue::default()),
+                live: AtomicUsize::new(0),
+                steals: AtomicUsize::new(0),
+                stacks: StackPool::default(),
+                timer_removals: Arc::new(RemoteRemovals::notifying(threads, move |worker| ring[worker].signal())),
+                bells,
+                sleeping: (0..threads).map(|_| AtomicBool::new(false)).collect(),
+            }),
+            next_queue: 0,
+            default_stack_size: STACK_SIZE,
+        }
+    }
+
+    /// Number of worker threads run() starts
+    pub fn threads(&self) -> usize {
+        self.shared.queues.len()
+    }
+
+    /// Adds a new coroutine, spreading spawns over the workers round-robin
+    ///
+    /// # Safety
+    /// The coroutine may resume on a different thread after any suspension
+    /// point. `func` must not hold anything that has to stay on one thread
+    /// (an Rc, a MutexGuard, a reference into a thread-local) across
+    /// yield_now(), park(), sleep(), join() or a blocking channel operation.
+    pub unsafe fn spawn<F, T>(&mut self, func: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let worker = self.next_queue;
+        self.next_queue = (self.next_queue + 1) % self.threads();
+        // SAFETY: passed on to our caller
+        unsafe { self.spawn_on(worker, SpawnOptions::new(), func) }
+    }
+
+    /// Adds a new coroutine to the queue of a specific worker
+    /// Other workers may still steal it once they run out of work. Only
+    /// the stack size of `options` applies: worker queues are plain FIFO.
+    ///
+    /// Panics if `worker` isn't below threads().
+    ///
+    /// # Safety
+    /// Same as spawn().
+    pub unsafe fn spawn_on<F, T>(&mut self, worker: usize, options: SpawnOptions, func: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        assert!(worker < self.threads(), "no worker {} on a scheduler with {} threads", worker, self.threads());
+
+        let size = options.stack_size.unwrap_or(self.default_stack_size);
+        let stack = self.shared.stacks.acquire(size);
+
+        let (coro, handle) = prepare_coroutine(func, stack, &self.shared.wakeups);
+        self.shared.live.fetch_add(1, Ordering::Relaxed);
+        self.shared.queues[worker].lock().unwrap().push_back(SendCoroutine(coro));
+        handle
+    }
+
+    /// How many times a worker took a coroutine from a peer's queue
+    pub fn steals(&self) -> usize {
+        self.shared.steals.load(Ordering::Relaxed)
+    }
+
+    /// Hit/miss counters of the scheduler's stack pool
+    pub fn pool_stats(&self) -> PoolStats {
+        self.shared.stacks.stats()
+    }
+
+    /// Starts the worker threads and waits for them
+    /// Returns once every coroutine has finished, or once all that are left
+    /// are Blocked with no timer armed to wake them (like Scheduler::run).
+    pub fn run(&mut self) {
+        let workers: Vec<_> = (0..self.threads())
+            .map(|index| {
+                let shared = self.shared.clone();
+                thread::Builder::new()
+                    .name(format!("coroutine-worker-{}", index))
+                    .spawn(move || Worker::new(index, shared).run())
+                    .expect("failed to start a worker thread")
+            })
+            .collect();
+
+        for worker in workers {
+            if let Err(payload) = worker.join() {
+                std::panic::resume_unwind(payload);
+            }
+        }
+    }
+}
+
+/// The state one worker thread keeps to itself
+struct Worker {
+    index: usize,
+    shared: Arc<Shared>,
+    timers: Rc<RefCell<TimerWheel>>,  // Deadlines armed by coroutines running here
+}
+
+impl Worker {
+    fn new(index: usize, shared: Arc<Shared>) -> Self {
+        let timers = TimerWheel::for_worker(index, shared.timer_removals.clone());
+        Worker {
+            index,
+            shared,
+            timers: Rc::new(RefCell::new(timers)),
+        }
+    }
+
+    fn run(self) {
+        // sleep() arms timers on whichever worker the coroutine is on
+        TIMERS.with(|t| t.replace(Some(self.timers.clone())));
+
+        loop {
+            if !self.timers.borrow().is_empty() {
+                self.timers.borrow_mut().advance(Instant::now());
+            }
+            self.process_wakeups();
+
+            let Some(SendCoroutine(mut coro)) = self.pop().or_else(|| self.steal()) else {
+                if self.finished() {
+                    break;
+                }
+                self.idle();
+                continue;
+            };
+
+            unsafe {
+                switch_into(&mut *coro);
+            }
+
+            match coro.state() {
+                CoroutineState::Suspended => self.push(SendCoroutine(coro)),
+                CoroutineState::Blocked => {
+                    let mut parking = self.shared.parking.lock().unwrap();
+                    if parking.woken_early.remove(&coro.id()) {
+                        drop(parking);
+                        self.push(SendCoroutine(coro));
+                    } else {
+                        parking.blocked.insert(coro.id(), SendCoroutine(coro));
+                        drop(parking);
+                        // Maybe the last one that was still running
+                        self.ring_sleepers();
+                    }
+                }
+                CoroutineState::Complete | CoroutineState::Panicked(_) | CoroutineState::Cancelled => {
+                    self.shared.parking.lock().unwrap().woken_early.remove(&coro.id());
+                    if let Some(stack) = coro.finish() {
+                        self.shared.stacks.release(stack);
+                    }
+                    self.shared.live.fetch_sub(1, Ordering::AcqRel);
+                    self.ring_sleepers();
+                }
+                _ => unreachable!(),
+            }
+        }
+
+        TIMERS.with(|t| t.replace(None));
+    }
+
+    fn push(&self, coro: SendCoroutine) {
+        self.shared.queues[self.index].lock().unwrap().push_back(coro);
+        self.ring_sleepers();
+    }
+
+    fn pop(&self) -> Option<SendCoroutine> {
+        self.shared.queues[self.index].lock().unwrap().pop_front()
+    }
+
+    /// Takes the oldest coroutine of the first peer that has one
+    /// Peers are visited starting right after us, so idle workers don't all
+    /// gang up on worker 0.
+    fn steal(&self) -> Option<SendCoroutine> {
+        let count = self.shared.queues.len();
+
+        (1..count).find_map(|offset| {
+            let victim = (self.index + offset) % count;
+            let coro = self.shared.queues[victim].lock().unwrap().pop_front()?;
+            self.shared.steals.fetch_add(1, Ordering::Relaxed);
+            Some(coro)
+        })
+    }
+
+    /// Moves woken coroutines from the parking lot to our own queue
+    fn process_wakeups(&self) {
+        let woken = mem::take(&mut *self.shared.wakeups.ids.lock().unwrap());
+        if woken.is_empty() {
+            return;
+        }
+
+        let mut parking = self.shared.parking.lock().unwrap();
+        for id in woken {
+            match parking.blocked.remove(&id) {
+                Some(coro) => self.push(coro),
+                // Not blocked (yet): remember, so its next park() returns
+                None => {
+                    parking.woken_early.insert(id);
+                }
+            }
+        }
+    }
+
+    /// Sleeps until a Waker fires, a peer rings our bell or our next timer
+    /// is due
+    fn idle(&self) {
+        let sleeping = &self.shared.sleeping[self.index];
+        sleeping.store(true, Ordering::SeqCst);
+        // Pairs with the fence in ring_sleepers(): a peer either sees us
+        // sleeping, or we see what it did before looking
+        atomic::fence(Ordering::SeqCst);
+
+        if !self.has_work() && !self.finished() {
+            let timeout = self
+                .timers
+                .borrow()
+                .next_deadline()
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            let bell = &self.shared.bells[self.index];
+            wait_readable(&[&self.shared.wakeups.eventfd, bell], timeout);
+
+            // Back at the top of the loop, the wakeups get drained after this
+            self.shared.wakeups.reset();
+            bell.reset();
+        }
+        sleeping.store(false, Ordering::Relaxed);
+    }
+
+    /// Wakes every peer sleeping in idle(), so it looks at what changed
+    /// Called after queueing a coroutine and after one finished or parked,
+    /// which may be what a sleeping peer's finished() waits for.
+    fn ring_sleepers(&self) {
+        atomic::fence(Ordering::SeqCst);
+        for (index, sleeping) in self.shared.sleeping.iter().enumerate() {
+            if index != self.index && sleeping.load(Ordering::Relaxed) {
+                self.shared.bells[index].signal();
+            }
+        }
+    }
+
+    /// Whether any queue has a coroutine or any wakeup is pending
+    fn has_work(&self) -> bool {
+        self.shared.queues.iter().any(|queue| !queue.lock().unwrap().is_empty())
+            || !self.shared.wakeups.ids.lock().unwrap().is_empty()
+    }
+
+    /// Whether this worker can stop
+    /// True when nothing is left at all, or when every remaining coroutine
+    /// is parked, no wakeup is pending and none of our timers can fire.
+    /// Workers that still have timers keep going and pick the sleepers up.
+    fn finished(&self) -> bool {
+        if !self.timers.borrow().is_empty() {
+            return false;
+        }
+
+        let parking = self.shared.parking.lock().unwrap();
+        let live = self.shared.live.load(Ordering::Acquire);
+        live == parking.blocked.len() && self.shared.wakeups.ids.lock().unwrap().is_empty()
+    }
+}
+
+/// Blocks until one of `fds` is readable or `timeout` (forever if None)
+/// has passed
+fn wait_readable(fds: &[&EventFd], timeout: Option<Duration>) {
+    let mut polled: Vec<_> = fds
+        .iter()
+        .map(|fd| libc::pollfd { fd: fd.as_raw_fd(), events: libc::POLLIN, revents: 0 })
+        .collect();
+    // Rounded up: a timer due in less than a millisecond mustn't spin
+    let timeout = timeout.map_or(-1, |timeout| timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32);
+    // EINTR is fine too, the caller looks again either way
+    unsafe { libc::poll(polled.as_mut_ptr(), polled.len() as libc::nfds_t, timeout) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sleep, unbounded, yield_now, JoinError};
+    use std::thread::ThreadId;
+
+    /// Notes the thread it gets dropped on
+    struct DroppedOn(Arc<Mutex<Option<ThreadId>>>);
+
+    impl Drop for DroppedOn {
+        fn drop(&mut self) {
+            *self.0.lock().unwrap() = Some(thread::current().id());
+        }
+    }
+
+    #[test]
+    fn a_sleeper_cancelled_on_another_worker_disarms_its_timer() {
+        let mut scheduler = MultiScheduler::new(2);
+        let armed_on = Arc::new(Mutex::new(None));
+        let unwound_on = Arc::new(Mutex::new(None));
+
+        // SAFETY (both spawns): nothing thread-bound is held across a
+        // suspension point
+        let sleeper = {
+            let (armed_on, unwound_on) = (armed_on.clone(), unwound_on.clone());
+            unsafe {
+                scheduler.spawn(move || {
+                    let _unwinding = DroppedOn(unwound_on);
+                    *armed_on.lock().unwrap() = Some(thread::current().id());
+                    sleep(Duration::from_secs(60));
+                })
+            }
+        };
+        let canceller = {
+            let armed_on = armed_on.clone();
+            unsafe {
+                scheduler.spawn(move || {
+                    // Get onto the sleeper's worker; once we run there, the
+                    // sleeper has gone to sleep
+                    while *armed_on.lock().unwrap() != Some(thread::current().id()) {
+                        yield_now();
+                    }
+                    sleeper.cancel();
+                    // Keep this worker busy, so the other one picks up the
+                    // wakeup and unwinds the sleeper
+                    let busy = Instant::now();
+                    while busy.elapsed() < Duration::from_millis(50) {}
+                    sleeper.join()
+                })
+            }
+        };
+
+        let started = Instant::now();
+        scheduler.run();
+        assert!(matches!(canceller.join().unwrap(), Err(JoinError::Cancelled)));
+        assert_ne!(*armed_on.lock().unwrap(), *unwound_on.lock().unwrap());
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn an_idle_worker_wakes_up_for_a_wakeup() {
+        let mut scheduler = MultiScheduler::new(2);
+        let (tx, rx) = unbounded();
+        let received = Arc::new(AtomicBool::new(false));
+
+        // SAFETY (both spawns): nothing thread-bound is held across a
+        // suspension point
+        let flag = received.clone();
+        unsafe {
+            scheduler.spawn(move || {
+                rx.recv().unwrap();
+                flag.store(true, Ordering::Release);
+            })
+        };
+        let sender = unsafe {
+            scheduler.spawn(move || {
+                // Never yields, so the other worker runs out of work and
+                // goes to sleep, and only it can run the receiver
+                let busy = Instant::now();
+                while busy.elapsed() < Duration::from_millis(20) {}
+                tx.send(()).unwrap();
+                while !received.load(Ordering::Acquire) && busy.elapsed() < Duration::from_secs(5) {}
+                busy.elapsed()
+            })
+        };
+
+        scheduler.run();
+        assert!(sender.join().unwrap() < Duration::from_secs(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "no worker 2")]
+    fn spawn_on_a_missing_worker_panics() {
+        let mut scheduler = MultiScheduler::new(2);
+        // SAFETY: the coroutine never runs
+        unsafe { scheduler.spawn_on(2, SpawnOptions::new(), || ()) };
+    }
+}
@@ -0,0 +1,411 @@
+//! Coroutine-friendly TCP
+//! Same shape as std::net, but sockets are non-blocking underneath: a call
+//! that would block parks just the calling coroutine until the reactor
+//! reports the socket ready, then retries. Anything that can block must
+//! therefore run inside a coroutine on a running Scheduler; MultiScheduler
+//! workers have no reactor.
+
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{Shutdown, SocketAddr, ToSocketAddrs};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+
+use super::reactor::{self, cvt, Interest};
+
+/// Pending connections the kernel queues for a listener
+/// Generous (the kernel caps it at net.core.somaxconn), so thousands of
+/// coroutines can connect at once without their SYNs getting dropped and
+/// retried a second later.
+const LISTEN_BACKLOG: libc::c_int = 4096;
+
+/// Runs `operation` until it stops reporting WouldBlock, parking the
+/// coroutine on `fd` in between
+fn retry<T>(fd: RawFd, interest: Interest, mut operation: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    loop {
+        match operation() {
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => reactor::wait(fd, interest)?,
+            result => return result,
+        }
+    }
+}
+
+/// Creates a non-blocking TCP socket for `addr`'s address family
+fn new_socket(addr: &SocketAddr) -> io::Result<OwnedFd> {
+    let family = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let fd = cvt(unsafe { libc::socket(family, libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC, 0) })?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Converts `addr` into the C representation bind() and connect() take
+fn raw_address(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let raw = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr { s_addr: u32::from_ne_bytes(addr.ip().octets()) },
+                sin_zero: [0; 8],
+            };
+            unsafe { ptr::write(ptr::addr_of_mut!(storage) as *mut libc::sockaddr_in, raw) };
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let raw = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: addr.flowinfo(),
+                sin6_addr: libc::in6_addr { s6_addr: addr.ip().octets() },
+                sin6_scope_id: addr.scope_id(),
+            };
+            unsafe { ptr::write(ptr::addr_of_mut!(storage) as *mut libc::sockaddr_in6, raw) };
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+/// Tries every address `addrs` resolves to until `attempt` succeeds
+fn each_address<T>(addrs: impl ToSocketAddrs, mut attempt: impl FnMut(&SocketAddr) -> io::Result<T>) -> io::Result<T> {
+    let mut last_error = None;
+    for addr in addrs.to_socket_addrs()? {
+        match attempt(&addr) {
+            Ok(value) => return Ok(value),
+            Err(error) => last_error = Some(error),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no addresses to try")))
+}
+
+/// A TCP socket waiting for connections
+#[derive(Debug)]
+pub struct TcpListener {
+    inner: std::net::TcpListener,
+}
+
+impl TcpListener {
+    /// Binds and starts listening; never blocks, so it works outside of
+    /// coroutines too
+    pub fn bind(addrs: impl ToSocketAddrs) -> io::Result<Self> {
+        each_address(addrs, |addr| {
+            let socket = new_socket(addr)?;
+            let fd = socket.as_raw_fd();
+
+            let on: libc::c_int = 1;
+            cvt(unsafe {
+                libc::setsockopt(
+                    fd,
+                    libc::SOL_SOCKET,
+                    libc::SO_REUSEADDR,
+                    &on as *const libc::c_int as *const libc::c_void,
+                    mem::size_of::<libc::c_int>() as libc::socklen_t,
+                )
+            })?;
+
+            let (raw, len) = raw_address(addr);
+            cvt(unsafe { libc::bind(fd, ptr::addr_of!(raw) as *const libc::sockaddr, len) })?;
+            cvt(unsafe { libc::listen(fd, LISTEN_BACKLOG) })?;
+
+            Ok(TcpListener { inner: socket.into() })
+        })
+    }
+
+    /// Waits for the next connection, parking the coroutine meanwhile
+    pub fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let (stream, addr) = retry(self.inner.as_raw_fd(), Interest::Readable, || self.inner.accept())?;
+        stream.set_nonblocking(true)?;
+        Ok((TcpStream { inner: stream }, addr))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+/// A TCP connection whose reads and writes only block the calling coroutine
+#[derive(Debug)]
+pub struct TcpStream {
+    inner: std::net::TcpStream,
+}
+
+impl TcpStream {
+    /// Opens a connection, parking the coroutine until it is established
+    pub fn connect(addrs: impl ToSocketAddrs) -> io::Result<Self> {
+        each_address(addrs, |addr| {
+            let stream = std::net::TcpStream::from(new_socket(addr)?);
+            let fd = stream.as_raw_fd();
+
+            let (raw, len) = raw_address(addr);
+            match cvt(unsafe { libc::connect(fd, ptr::addr_of!(raw) as *const libc::sockaddr, len) }) {
+                Ok(_) => {}
+                // The handshake is under way: it's done once we can write,
+                // but a wakeup alone doesn't prove we can
+                Err(error) if error.raw_os_error() == Some(libc::EINPROGRESS) => loop {
+                    reactor::wait(fd, Interest::Writable)?;
+                    if let Some(error) = stream.take_error()? {
+                        return Err(error);
+                    }
+                    match stream.peer_addr() {
+                        Ok(_) => break,
+                        Err(error) if error.raw_os_error() == Some(libc::ENOTCONN) => {}
+                        Err(error) => return Err(error),
+                    }
+                },
+                Err(error) => return Err(error),
+            }
+
+            match stream.take_error()? {
+                Some(error) => Err(error),
+                None => Ok(TcpStream { inner: stream }),
+            }
+        })
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    /// Closes the read half, the write half or both; never blocks
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.inner.set_nodelay(nodelay)
+    }
+}
+
+// Implemented on &TcpStream as well, like std, so one coroutine can read
+// while another writes through a shared reference
+impl Read for &TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        retry(self.inner.as_raw_fd(), Interest::Readable, || (&self.inner).read(buf))
+    }
+}
+
+impl Write for &TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        retry(self.inner.as_raw_fd(), Interest::Writable, || (&self.inner).write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sleep, unbounded, yield_now, JoinError, Scheduler};
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Echoes everything until the peer closes its write half
+    fn echo(mut stream: TcpStream) {
+        let mut buf = [0u8; 1024];
+        loop {
+            match stream.read(&mut buf).unwrap() {
+                0 => break,
+                n => stream.write_all(&buf[..n]).unwrap(),
+            }
+        }
+    }
+
+    #[test]
+    fn echo_over_loopback() {
+        let mut scheduler = Scheduler::new();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        scheduler.spawn(move || echo(listener.accept().unwrap().0));
+        let client = scheduler.spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(b"hello, reactor").unwrap();
+            stream.shutdown(Shutdown::Write).unwrap();
+
+            let mut reply = Vec::new();
+            stream.read_to_end(&mut reply).unwrap();
+            reply
+        });
+
+        scheduler.run();
+        assert_eq!(client.join().unwrap(), b"hello, reactor");
+    }
+
+    #[test]
+    fn waiting_for_data_only_blocks_the_reader() {
+        let mut scheduler = Scheduler::new();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let ticks = Rc::new(Cell::new(0));
+
+        // The server only answers after a while
+        scheduler.spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            sleep(Duration::from_millis(20));
+            stream.write_all(b"late").unwrap();
+        });
+
+        let counter = ticks.clone();
+        scheduler.spawn(move || {
+            for _ in 0..10 {
+                counter.set(counter.get() + 1);
+                yield_now();
+            }
+        });
+
+        let observed = ticks.clone();
+        let reader = scheduler.spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).unwrap();
+            // Everyone else kept running while we waited
+            (buf, observed.get())
+        });
+
+        scheduler.run();
+        assert_eq!(reader.join().unwrap(), (*b"late", 10));
+    }
+
+    #[test]
+    fn many_concurrent_connections() {
+        const CLIENTS: usize = 200;
+
+        let mut scheduler = Scheduler::builder().stack_size(64 * 1024).build();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // One acceptor hands connections to a handler coroutine each
+        let (tx, rx) = unbounded();
+        scheduler.spawn(move || {
+            for _ in 0..CLIENTS {
+                tx.send(listener.accept().unwrap().0).unwrap();
+            }
+        });
+        let rx = Rc::new(rx);
+        for _ in 0..CLIENTS {
+            let rx = rx.clone();
+            scheduler.spawn(move || echo(rx.recv().unwrap()));
+        }
+
+        let clients: Vec<_> = (0..CLIENTS)
+            .map(|id| {
+                scheduler.spawn(move || {
+                    let mut stream = TcpStream::connect(addr).unwrap();
+                    let message = format!("client {}", id);
+                    stream.write_all(message.as_bytes()).unwrap();
+                    stream.shutdown(Shutdown::Write).unwrap();
+
+                    let mut reply = String::new();
+                    stream.read_to_string(&mut reply).unwrap();
+                    reply == message
+                })
+            })
+            .collect();
+
+        scheduler.run();
+        assert!(clients.into_iter().all(|client| client.join().unwrap()));
+    }
+
+    #[test]
+    fn connect_to_a_closed_port_fails() {
+        let mut scheduler = Scheduler::new();
+
+        // Grab a free port, then close it again
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        let client = scheduler.spawn(move || TcpStream::connect(addr).map(|_| ()).unwrap_err().kind());
+        scheduler.run();
+        assert_eq!(client.join().unwrap(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn connect_outlasts_a_spurious_wakeup() {
+        let mut scheduler = Scheduler::new();
+
+        // With a backlog of 0 and one connection queued, the listener drops
+        // further handshakes, so a connect stays in progress
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        cvt(unsafe { libc::listen(listener.as_raw_fd(), 0) }).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _queued = std::net::TcpStream::connect(addr).unwrap();
+
+        let connector = scheduler.spawn(move || TcpStream::connect(addr).map(|_| ()));
+        let wakeups = scheduler.wakeups.clone();
+        let poker = scheduler.spawn(move || {
+            sleep(Duration::from_millis(20));
+            // Wakes the connector with nothing having happened
+            wakeups.push(connector.id());
+            sleep(Duration::from_millis(20));
+            let still_connecting = !connector.is_finished();
+            connector.cancel();
+            still_connecting
+        });
+
+        scheduler.run();
+        assert!(poker.join().unwrap());
+    }
+
+    #[test]
+    fn cancelling_blocked_io_lets_run_return() {
+        // A scheduler stuck in epoll_wait() would hang the test, so it runs
+        // on a thread of its own that we only wait for so long
+        let (done, finished) = mpsc::channel();
+        thread::spawn(move || {
+            let mut scheduler = Scheduler::new();
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            // Connections to this one sit in its backlog; nobody ever writes
+            let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = silent.local_addr().unwrap();
+
+            let acceptor = scheduler.spawn(move || listener.accept().map(|_| ()));
+            let reader = scheduler.spawn(move || {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                stream.read(&mut [0u8; 16]).map(|_| ())
+            });
+            let canceller = scheduler.spawn(move || {
+                sleep(Duration::from_millis(20));
+                acceptor.cancel();
+                reader.cancel();
+                (acceptor.join(), reader.join())
+            });
+
+            scheduler.run();
+            let (accepted, read) = canceller.join().unwrap();
+            done.send(matches!(accepted, Err(JoinError::Cancelled)) && matches!(read, Err(JoinError::Cancelled)))
+                .unwrap();
+        });
+
+        let cancelled = finished
+            .recv_timeout(Duration::from_secs(10))
+            .expect("run() kept waiting for the cancelled coroutines");
+        assert!(cancelled);
+    }
+}
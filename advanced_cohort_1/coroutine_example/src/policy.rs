@@ -0,0 +1,270 @@
+//! Scheduling policies
+//! The Scheduler decides *when* a coroutine can run (spawned, yielded or
+//! woken); a policy decides *which* runnable coroutine goes next. Keeping
+//! the two apart lets the same coroutines be run under different policies
+//! to compare starvation and latency:
+//! - RoundRobin: strict FIFO, everybody takes turns (the default)
+//! - PriorityQueue: highest priority first, FIFO among equals; low
+//!   priorities starve for as long as higher ones stay runnable
+//! - FairShare: CPU time split in proportion to each coroutine's weight
+//! - EarliestDeadlineFirst: the coroutine whose deadline is closest runs
+//!   next; coroutines without a deadline only run when nobody else can
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use super::{AnyCoroutine, CoroutineState};
+
+/// Weight of coroutines spawned without one
+pub const DEFAULT_WEIGHT: u32 = 1;
+
+/// Picks the next coroutine to run among the runnable ones
+/// Implement this to try out a policy of your own.
+pub trait SchedulingPolicy {
+    /// A task became runnable: it was spawned, it yielded or it was woken
+    fn push(&mut self, task: Task);
+
+    /// Removes the task that should run next
+    fn pop(&mut self) -> Option<Task>;
+
+    /// Number of runnable tasks
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Called after every time slice with how long the task just ran
+    /// The task's state tells whether it will come back. Most policies
+    /// don't care; fair-share ones do their bookkeeping here.
+    fn charge(&mut self, _task: &Task, _ran: Duration) {}
+}
+
+/// A runnable coroutine together with what it asked for at spawn time
+pub struct Task {
+    coro: Box<dyn AnyCoroutine>,
+    priority: i32,
+    deadline: Option<Instant>,
+    weight: u32,
+}
+
+impl Task {
+    pub(crate) fn new(coro: Box<dyn AnyCoroutine>, priority: i32, deadline: Option<Instant>, weight: u32) -> Self {
+        Task { coro, priority, deadline, weight }
+    }
+
+    pub(crate) fn coroutine(&mut self) -> &mut (dyn AnyCoroutine + 'static) {
+        &mut *self.coro
+    }
+
+    pub(crate) fn into_coroutine(self) -> Box<dyn AnyCoroutine> {
+        self.coro
+    }
+
+    pub fn id(&self) -> usize {
+        self.coro.id()
+    }
+
+    pub fn state(&self) -> &CoroutineState {
+        self.coro.state()
+    }
+
+    /// Higher runs first under PriorityQueue
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    /// Used by EarliestDeadlineFirst
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Relative CPU share under FairShare
+    pub fn weight(&self) -> u32 {
+        self.weight
+    }
+}
+
+/// Strict FIFO: the order the Scheduler has always used
+#[derive(Default)]
+pub struct RoundRobin {
+    queue: VecDeque<Task>,
+}
+
+impl RoundRobin {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SchedulingPolicy for RoundRobin {
+    fn push(&mut self, task: Task) {
+        self.queue.push_back(task);
+    }
+
+    fn pop(&mut self) -> Option<Task> {
+        self.queue.pop_front()
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+/// A heap entry: ordered by `key` first, then by arrival so that equal
+/// keys stay FIFO
+struct Entry<K> {
+    key: K,
+    sequence: Reverse<u64>,
+    task: Task,
+}
+
+impl<K: Ord> PartialEq for Entry<K> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<K: Ord> Eq for Entry<K> {}
+
+impl<K: Ord> PartialOrd for Entry<K> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K: Ord> Ord for Entry<K> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (&self.key, self.sequence).cmp(&(&other.key, other.sequence))
+    }
+}
+
+/// Max-heap of tasks by a policy-specific key
+struct TaskHeap<K> {
+    heap: BinaryHeap<Entry<K>>,
+    next_sequence: u64,
+}
+
+impl<K: Ord> Default for TaskHeap<K> {
+    fn default() -> Self {
+        TaskHeap { heap: BinaryHeap::new(), next_sequence: 0 }
+    }
+}
+
+impl<K: Ord> TaskHeap<K> {
+    fn push(&mut self, key: K, task: Task) {
+        let sequence = Reverse(self.next_sequence);
+        self.next_sequence += 1;
+        self.heap.push(Entry { key, sequence, task });
+    }
+
+    fn pop(&mut self) -> Option<(K, Task)> {
+        self.heap.pop().map(|entry| (entry.key, entry.task))
+    }
+}
+
+/// Highest priority first
+/// A coroutine that keeps yielding at a high priority keeps the CPU;
+/// that starvation is the trade-off this policy is here to show.
+#[derive(Default)]
+pub struct PriorityQueue {
+    heap: TaskHeap<i32>,
+}
+
+impl PriorityQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SchedulingPolicy for PriorityQueue {
+    fn push(&mut self, task: Task) {
+        self.heap.push(task.priority, task);
+    }
+
+    fn pop(&mut self) -> Option<Task> {
+        self.heap.pop().map(|(_, task)| task)
+    }
+
+    fn len(&self) -> usize {
+        self.heap.heap.len()
+    }
+}
+
+/// Weighted fair share, in the style of stride scheduling
+/// Every task has a virtual time that grows with the CPU time it used,
+/// divided by its weight; the task furthest behind runs next. A task
+/// with weight 3 therefore gets three times the CPU of a weight 1 task.
+#[derive(Default)]
+pub struct FairShare {
+    heap: TaskHeap<Reverse<u64>>,
+    virtual_times: HashMap<usize, u64>,  // Per task, in weighted nanoseconds
+    floor: u64,                          // Virtual time of the task picked last
+}
+
+impl FairShare {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SchedulingPolicy for FairShare {
+    fn push(&mut self, task: Task) {
+        // Newcomers and long sleepers start at the current floor, so they
+        // can't bank credit and then monopolize the CPU
+        let time = self.virtual_times.entry(task.id()).or_insert(self.floor);
+        *time = (*time).max(self.floor);
+        self.heap.push(Reverse(*time), task);
+    }
+
+    fn pop(&mut self) -> Option<Task> {
+        let (Reverse(time), task) = self.heap.pop()?;
+        self.floor = time;
+        Some(task)
+    }
+
+    fn len(&self) -> usize {
+        self.heap.heap.len()
+    }
+
+    fn charge(&mut self, task: &Task, ran: Duration) {
+        if task.state().is_finished() {
+            self.virtual_times.remove(&task.id());
+            return;
+        }
+
+        let weight = u128::from(task.weight.max(1));
+        let time = self.virtual_times.entry(task.id()).or_insert(self.floor);
+        *time += (ran.as_nanos() / weight) as u64;
+    }
+}
+
+/// Earliest deadline first
+/// Tasks without a deadline sort after every task that has one.
+#[derive(Default)]
+pub struct EarliestDeadlineFirst {
+    heap: TaskHeap<Reverse<(bool, Option<Instant>)>>,
+}
+
+impl EarliestDeadlineFirst {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SchedulingPolicy for EarliestDeadlineFirst {
+    fn push(&mut self, task: Task) {
+        // (false, Some(_)) < (true, None): deadlines first, earliest on top
+        let key = Reverse((task.deadline.is_none(), task.deadline));
+        self.heap.push(key, task);
+    }
+
+    fn pop(&mut self) -> Option<Task> {
+        self.heap.pop().map(|(_, task)| task)
+    }
+
+    fn len(&self) -> usize {
+        self.heap.heap.len()
+    }
+}
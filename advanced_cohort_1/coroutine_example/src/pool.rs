@@ -0,0 +1,97 @@
+//! Stack recycling
+//! Mapping and unmapping a multi-megabyte stack costs a couple of syscalls
+//! and page faults every time. When thousands of short-lived coroutines come
+//! and go, it is much cheaper to hand a finished coroutine's stack straight
+//! to the next one. The pool keeps a bounded free list per stack size.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use super::Stack;
+
+/// How many idle stacks of each size a pool keeps by default
+pub const DEFAULT_POOL_CAPACITY: usize = 64;
+
+/// Counters describing how well a pool is doing
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PoolStats {
+    pub hits: usize,    // Requests served from the free list
+    pub misses: usize,  // Requests that had to map a new stack
+    pub cached: usize,  // Stacks currently idle in the pool
+}
+
+/// A shareable pool of reusable stacks
+/// Cloning gives another handle to the same pool, so several schedulers
+/// (or threads) can recycle stacks between each other.
+#[derive(Clone)]
+pub struct StackPool {
+    inner: Arc<Mutex<PoolInner>>,
+}
+
+struct PoolInner {
+    free: HashMap<usize, Vec<Stack>>,  // Idle stacks, keyed by requested size
+    capacity: usize,                   // Max idle stacks kept per size
+    stats: PoolStats,
+}
+
+impl Default for StackPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_POOL_CAPACITY)
+    }
+}
+
+impl StackPool {
+    /// Creates a pool keeping at most `capacity` idle stacks of each size
+    /// A capacity of 0 disables recycling altogether.
+    pub fn new(capacity: usize) -> Self {
+        StackPool {
+            inner: Arc::new(Mutex::new(PoolInner {
+                free: HashMap::new(),
+                capacity,
+                stats: PoolStats::default(),
+            })),
+        }
+    }
+
+    /// Hands out a stack of at least `size` bytes, reusing one if possible
+    pub(crate) fn acquire(&self, size: usize) -> Stack {
+        let size = Stack::round_to_pages(size);
+        let mut inner = self.inner.lock().unwrap();
+
+        if let Some(stack) = inner.free.get_mut(&size).and_then(Vec::pop) {
+            inner.stats.hits += 1;
+            inner.stats.cached -= 1;
+            return stack;
+        }
+
+        inner.stats.misses += 1;
+        drop(inner);
+        Stack::new(size)
+    }
+
+    /// Takes back a stack that no code is running on anymore
+    /// Stacks beyond the pool's capacity are simply unmapped.
+    pub(crate) fn release(&self, stack: Stack) {
+        let mut inner = self.inner.lock().unwrap();
+        let capacity = inner.capacity;
+        let free = inner.free.entry(stack.size).or_default();
+
+        if free.len() < capacity {
+            free.push(stack);
+            inner.stats.cached += 1;
+        }
+    }
+
+    /// Returns a snapshot of the pool's counters
+    pub fn stats(&self) -> PoolStats {
+        self.inner.lock().unwrap().stats
+    }
+}
+
+/// Pool shared by every generator in the process
+/// Generators are not tied to a scheduler, so they need a home of their own.
+pub fn generator_pool() -> &'static StackPool {
+    static POOL: OnceLock<StackPool> = OnceLock::new();
+    POOL.get_or_init(StackPool::default)
+}
+
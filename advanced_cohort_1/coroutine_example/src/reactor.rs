@@ -0,0 +1,217 @@
+//! epoll reactor
+//! A blocking read() stops the whole OS thread, and with it every coroutine
+//! on the scheduler. Sockets are therefore switched to non-blocking mode and
+//! the reactor takes over the waiting:
+//! 1. An operation that would block leaves the coroutine's Waker with the
+//!    reactor, registers the fd with epoll and parks
+//! 2. When the scheduler has nothing runnable, it sleeps in epoll_wait()
+//!    instead, until an fd becomes ready, a timer is due or some Waker fires
+//! 3. Ready fds wake their waiters, which retry the operation
+//!
+//! Fds are registered edge-triggered for both directions. Because a
+//! coroutine always tries the operation before waiting, an edge that
+//! arrived while nobody was waiting is never lost: the retry sees the data.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::Waker;
+use std::time::Duration;
+
+use super::{current_waker, park, WakeQueue};
+
+/// epoll_wait() batch size
+const MAX_EVENTS: usize = 256;
+
+/// Marks the WakeQueue's eventfd among the returned events
+const WAKE_TOKEN: u64 = u64::MAX;
+
+thread_local! {
+    /// Reactor of the scheduler running on this thread, for socket I/O
+    static CURRENT: RefCell<Option<Rc<Reactor>>> = const { RefCell::new(None) };
+}
+
+/// Which readiness a coroutine waits for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interest {
+    Readable,
+    Writable,
+}
+
+/// Coroutines waiting on one fd
+#[derive(Default)]
+struct Waiters {
+    readers: Vec<Waker>,
+    writers: Vec<Waker>,
+}
+
+pub struct Reactor {
+    epoll: OwnedFd,
+    waiters: RefCell<HashMap<RawFd, Waiters>>,
+    wakeups: Arc<WakeQueue>,  // Its eventfd interrupts epoll_wait()
+}
+
+impl Reactor {
+    /// Creates a reactor that also wakes up whenever `wakeups` gets an id
+    pub fn new(wakeups: Arc<WakeQueue>) -> io::Result<Self> {
+        let epoll = cvt(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+        let reactor = Reactor {
+            epoll: unsafe { OwnedFd::from_raw_fd(epoll) },
+            waiters: RefCell::new(HashMap::new()),
+            wakeups,
+        };
+
+        // Level-triggered: it stays ready until poll() drains it
+        reactor.control(reactor.wakeups.eventfd.as_raw_fd(), libc::EPOLLIN as u32, WAKE_TOKEN)?;
+        Ok(reactor)
+    }
+
+    fn control(&self, fd: RawFd, events: u32, token: u64) -> io::Result<()> {
+        let mut event = libc::epoll_event { events, u64: token };
+        cvt(unsafe { libc::epoll_ctl(self.epoll.as_raw_fd(), libc::EPOLL_CTL_ADD, fd, &mut event) })?;
+        Ok(())
+    }
+
+    /// Whether any coroutine is waiting for an fd
+    pub fn has_waiters(&self) -> bool {
+        !self.waiters.borrow().is_empty()
+    }
+
+    /// Wakes `waker` once `fd` is ready for `interest`
+    fn register(&self, fd: RawFd, interest: Interest, waker: Waker) -> io::Result<()> {
+        // Registering again is harmless; checking the kernel's answer beats
+        // keeping our own list, which a closed and reused fd would fool
+        let events = libc::EPOLLIN | libc::EPOLLOUT | libc::EPOLLRDHUP | libc::EPOLLET;
+        match self.control(fd, events as u32, fd as u64) {
+            Err(error) if error.raw_os_error() != Some(libc::EEXIST) => return Err(error),
+            _ => {}
+        }
+
+        let mut waiters = self.waiters.borrow_mut();
+        let waiters = waiters.entry(fd).or_default();
+        match interest {
+            Interest::Readable => waiters.readers.push(waker),
+            Interest::Writable => waiters.writers.push(waker),
+        }
+        Ok(())
+    }
+
+    /// Forgets `waker` as a waiter for `interest` on `fd`, if it still is one
+    fn deregister(&self, fd: RawFd, interest: Interest, waker: &Waker) {
+        let mut waiters = self.waiters.borrow_mut();
+        let Some(entry) = waiters.get_mut(&fd) else {
+            return;
+        };
+        let line = match interest {
+            Interest::Readable => &mut entry.readers,
+            Interest::Writable => &mut entry.writers,
+        };
+        line.retain(|waiting| !waiting.will_wake(waker));
+        if entry.readers.is_empty() && entry.writers.is_empty() {
+            waiters.remove(&fd);
+        }
+    }
+
+    /// Waits up to `timeout` (forever if None) for fd events and wakes
+    /// whoever waits for them
+    pub fn poll(&self, timeout: Option<Duration>) -> io::Result<()> {
+        // Round up, or a sub-millisecond timer would make us spin
+        let timeout = match timeout {
+            Some(timeout) => timeout.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+        let ready = unsafe {
+            libc::epoll_wait(self.epoll.as_raw_fd(), events.as_mut_ptr(), MAX_EVENTS as i32, timeout)
+        };
+        let ready = match cvt(ready) {
+            Ok(ready) => ready as usize,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => 0,
+            Err(error) => return Err(error),
+        };
+
+        let mut waiters = self.waiters.borrow_mut();
+        for event in &events[..ready] {
+            let (flags, token) = (event.events as i32, event.u64);
+            if token == WAKE_TOKEN {
+                self.wakeups.reset();
+                continue;
+            }
+
+            let Some(entry) = waiters.get_mut(&(token as RawFd)) else {
+                continue;
+            };
+            // Errors and hang-ups wake both sides; the retry reports them
+            let failed = flags & (libc::EPOLLERR | libc::EPOLLHUP) != 0;
+            if failed || flags & (libc::EPOLLIN | libc::EPOLLRDHUP) != 0 {
+                entry.readers.drain(..).for_each(Waker::wake);
+            }
+            if failed || flags & libc::EPOLLOUT != 0 {
+                entry.writers.drain(..).for_each(Waker::wake);
+            }
+            if entry.readers.is_empty() && entry.writers.is_empty() {
+                waiters.remove(&(token as RawFd));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Makes `reactor` the one socket I/O on this thread uses, returning the
+/// previous one for leave()
+pub fn enter(reactor: Rc<Reactor>) -> Option<Rc<Reactor>> {
+    CURRENT.with(|current| current.replace(Some(reactor)))
+}
+
+/// Restores the reactor that was current before the matching enter()
+pub fn leave(previous: Option<Rc<Reactor>>) {
+    CURRENT.with(|current| *current.borrow_mut() = previous);
+}
+
+/// Parks the current coroutine until `fd` is ready for `interest`
+/// Only call this after the operation itself reported WouldBlock.
+pub fn wait(fd: RawFd, interest: Interest) -> io::Result<()> {
+    let reactor = CURRENT
+        .with(|current| current.borrow().clone())
+        .expect("socket I/O would block outside of a running Scheduler");
+    let waker = current_waker();
+    reactor.register(fd, interest, waker.clone())?;
+    drop(reactor);
+
+    let _waiting = Waiting { fd, interest, waker };
+    park();
+    Ok(())
+}
+
+/// Withdraws a waiter from the reactor once it stops waiting
+/// After a readiness event poll() already did, but a cancelled coroutine's
+/// Waker would otherwise stay, and has_waiters() with it: the scheduler
+/// would block in epoll_wait() for a coroutine that is long gone.
+struct Waiting {
+    fd: RawFd,
+    interest: Interest,
+    waker: Waker,
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        CURRENT.with(|current| {
+            if let Some(reactor) = current.borrow().as_ref() {
+                reactor.deregister(self.fd, self.interest, &self.waker);
+            }
+        });
+    }
+}
+
+/// Turns a libc -1 return into the matching io::Error
+pub fn cvt(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
@@ -0,0 +1,239 @@
+//! Structured concurrency
+//! Scheduler::spawn takes 'static closures, because nothing stops the
+//! caller from returning while the coroutine still runs. A scope closes
+//! that gap the way std::thread::scope does for threads:
+//! 1. Coroutines spawned on the scope may borrow anything that outlives it,
+//!    including locals of the function that opened the scope
+//! 2. scope() runs the scheduler until every one of them has finished,
+//!    so no borrow can outlive what it points to
+//! 3. If the scope's body panics, the children are cancelled and waited
+//!    for before the panic carries on
+//! 4. If a child panics and nobody joined it, its panic carries on out of
+//!    scope() once everyone is done, like a scoped thread's does
+//!
+//! Children can spawn siblings through the scope too; they are picked up
+//! before scope() checks whether everyone is done.
+
+use std::cell::{Cell, RefCell};
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+use super::{
+    policy, prepare_scoped_coroutine, CancelFlag, JoinHandle, JoinSlot, Scheduler, SpawnOptions, StackPool,
+    Task, WakeQueue,
+};
+
+/// Spawns coroutines that may borrow from the caller's stack
+/// Created by Scheduler::scope(), which waits for all of them.
+pub struct Scope<'scope, 'env: 'scope> {
+    wakeups: Arc<WakeQueue>,
+    stacks: StackPool,
+    default_stack_size: usize,
+    spawned: RefCell<Vec<Task>>,            // Not handed to the scheduler yet
+    children: RefCell<Vec<Child>>,          // For cancelling them all at once
+    running: Cell<usize>,                   // Children that haven't finished
+    // Invariant in both lifetimes, like std::thread::Scope
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+/// What the scope keeps of every child it spawned
+struct Child {
+    cancel: Arc<CancelFlag>,
+    slot: Arc<dyn JoinSlot>,  // Where an unjoined panic would sit
+}
+
+/// Counts a child as finished once its closure is gone
+/// The closure owns it, so it goes whether the closure returned,
+/// panicked, was cancelled mid-way or was dropped before it ever ran.
+struct Running<'scope>(&'scope Cell<usize>);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() - 1);
+    }
+}
+
+impl<'scope> Scope<'scope, '_> {
+    /// Adds a coroutine that may borrow anything living longer than the scope
+    pub fn spawn<F, T>(&'scope self, func: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + 'scope,
+        T: 'scope,
+    {
+        self.spawn_with(SpawnOptions::new(), func)
+    }
+
+    /// Same as spawn(), with per-spawn options
+    pub fn spawn_with<F, T>(&'scope self, options: SpawnOptions, func: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + 'scope,
+        T: 'scope,
+    {
+        self.running.set(self.running.get() + 1);
+        let running = Running(&self.running);
+        let body = move || {
+            let _running = running;
+            func()
+        };
+
+        let size = options.stack_size.unwrap_or(self.default_stack_size);
+        let stack = self.stacks.acquire(size);
+        // SAFETY: Scheduler::scope() doesn't return before `running` is back
+        // to zero, and it only gets there once every child is done
+        let (coro, handle) = unsafe { prepare_scoped_coroutine(body, stack, &self.wakeups) };
+
+        let slot: Arc<dyn JoinSlot + 'scope> = handle.state.clone();
+        self.children.borrow_mut().push(Child {
+            cancel: handle.cancel.clone(),
+            // SAFETY: as above; the scope itself goes right after that
+            slot: unsafe { mem::transmute::<Arc<dyn JoinSlot + 'scope>, Arc<dyn JoinSlot>>(slot) },
+        });
+        let weight = options.weight.unwrap_or(policy::DEFAULT_WEIGHT);
+        self.spawned
+            .borrow_mut()
+            .push(Task::new(coro, options.priority, options.deadline, weight));
+        handle
+    }
+
+    /// Cancels every child that is still running
+    pub fn cancel_all(&self) {
+        if self.running.get() > 0 {
+            self.children.borrow().iter().for_each(|child| child.cancel.request());
+        }
+    }
+}
+
+impl Scheduler {
+    /// Runs `body`, then the scheduler until every coroutine spawned on
+    /// the scope has finished
+    /// Other coroutines on this scheduler run meanwhile too, but scope()
+    /// returns as soon as its own children are done; a later run() takes
+    /// care of the rest. Like join(), it waits forever for a child that
+    /// is blocked on something that never happens.
+    ///
+    /// Panics with the child's payload if a child panicked and its
+    /// JoinHandle wasn't joined by then.
+    ///
+    /// Children may borrow what outlives the scope, but nothing that only
+    /// lives inside it:
+    ///
+    /// ```compile_fail
+    /// use coroutine_example::Scheduler;
+    ///
+    /// let mut scheduler = Scheduler::new();
+    /// scheduler.scope(|s| {
+    ///     let local = vec![1, 2, 3];
+    ///     s.spawn(|| local.len());
+    /// });
+    /// ```
+    pub fn scope<'env, F, R>(&'env mut self, body: F) -> R
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> R,
+    {
+        let scope = Scope {
+            wakeups: self.wakeups.clone(),
+            stacks: self.stacks.clone(),
+            default_stack_size: self.default_stack_size,
+            spawned: RefCell::new(Vec::new()),
+            children: RefCell::new(Vec::new()),
+            running: Cell::new(0),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| body(&scope)));
+        if result.is_err() {
+            scope.cancel_all();
+        }
+
+        let entered = self.enter();
+        loop {
+            for task in scope.spawned.take() {
+                self.enqueue(task);
+            }
+            if scope.running.get() == 0 {
+                break;
+            }
+            if !self.run_once() {
+                self.wait_for_events(self.next_deadline());
+            }
+        }
+        self.leave(entered);
+
+        let result = result.unwrap_or_else(|payload| panic::resume_unwind(payload));
+        if let Some(payload) = scope.children.take().iter().find_map(|child| child.slot.take_panic()) {
+            panic::resume_unwind(payload);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sleep, yield_now, JoinError};
+    use std::time::Duration;
+
+    #[test]
+    fn scope_waits_for_every_child() {
+        let mut scheduler = Scheduler::new();
+        let finished = RefCell::new(Vec::new());
+
+        scheduler.scope(|s| {
+            let finished = &finished;
+            s.spawn(move || {
+                sleep(Duration::from_millis(10));
+                finished.borrow_mut().push("sleeper");
+            });
+            s.spawn(move || {
+                for _ in 0..3 {
+                    yield_now();
+                }
+                // Grandchildren count too
+                s.spawn(move || finished.borrow_mut().push("grandchild"));
+                finished.borrow_mut().push("yielder");
+            });
+        });
+
+        assert_eq!(finished.into_inner(), ["yielder", "grandchild", "sleeper"]);
+    }
+
+    #[test]
+    fn a_child_panic_carries_on_out_of_the_scope() {
+        let mut scheduler = Scheduler::new();
+        let sibling_done = Cell::new(false);
+
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+            scheduler.scope(|s| {
+                s.spawn(|| {
+                    yield_now();
+                    panic!("child failed");
+                });
+                s.spawn(|| {
+                    sleep(Duration::from_millis(10));
+                    sibling_done.set(true);
+                });
+            })
+        }));
+
+        let payload = outcome.unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"child failed"));
+        // The scope still waited for everyone before unwinding
+        assert!(sibling_done.get());
+    }
+
+    #[test]
+    fn a_joined_child_panic_stays_with_the_joiner() {
+        let mut scheduler = Scheduler::new();
+
+        let joined = scheduler.scope(|s| {
+            let child = s.spawn(|| -> () { panic!("child failed") });
+            s.spawn(move || matches!(child.join(), Err(JoinError::Panicked(_))))
+        });
+
+        assert!(joined.join().unwrap());
+    }
+}
@@ -0,0 +1,194 @@
+//! Hashed timer wheel
+//! Time is cut into fixed ticks and the wheel has one slot per tick, reused
+//! round-robin: a timer due at tick t lives in slot t % slots. Inserting is
+//! O(1), and advancing the clock only looks at the slots whose ticks have
+//! passed instead of every timer.
+//!
+//! Timers further away than one full turn simply share a slot with nearer
+//! ones; each entry keeps its exact deadline, so a slot visit only fires
+//! the entries that are really due and leaves the rest for a later turn.
+//!
+//! A wheel is only ever touched by the thread it belongs to. On a
+//! MultiScheduler a sleeping coroutine may wake up on another worker,
+//! though, so every TimerId records which worker's wheel it came from,
+//! and removing it anywhere else leaves it in that worker's inbox.
+
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::task::Waker;
+
+/// Resolution of the scheduler's timers
+pub const DEFAULT_TICK: Duration = Duration::from_millis(1);
+
+/// Number of slots, i.e. ticks covered by one turn of the wheel
+pub const DEFAULT_SLOTS: usize = 512;
+
+struct TimerEntry {
+    id: u64,
+    deadline: Instant,
+    waker: Waker,
+}
+
+/// Ids are unique across all wheels, so a TimerId handed to the wrong
+/// wheel (a coroutine that moved threads) can never remove someone else's
+static NEXT_TIMER_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Identifies an armed timer, for removing it before it fires
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimerId {
+    slot: usize,
+    id: u64,
+    worker: usize,  // Whose wheel it is armed on
+}
+
+/// Timers removed by other threads than their wheel's, one inbox per
+/// worker; each wheel empties its own on advance()
+pub struct RemoteRemovals {
+    inboxes: Vec<Mutex<Vec<TimerId>>>,
+    notify: Option<Box<dyn Fn(usize) + Send + Sync>>,  // Told whose inbox got a timer
+}
+
+impl RemoteRemovals {
+    pub fn new(workers: usize) -> Self {
+        RemoteRemovals {
+            inboxes: (0..workers).map(|_| Mutex::new(Vec::new())).collect(),
+            notify: None,
+        }
+    }
+
+    /// Like new(), and calls `notify` with the worker whose inbox just got
+    /// a timer, so a worker idling until that timer's deadline can wake up
+    pub fn notifying(workers: usize, notify: impl Fn(usize) + Send + Sync + 'static) -> Self {
+        RemoteRemovals { notify: Some(Box::new(notify)), ..Self::new(workers) }
+    }
+}
+
+pub struct TimerWheel {
+    slots: Vec<Vec<TimerEntry>>,
+    tick: Duration,
+    start: Instant,     // Tick 0 starts here
+    current_tick: u64,  // Every tick before this one has been processed
+    len: usize,         // Timers currently armed
+    worker: usize,      // Recorded in every TimerId we hand out
+    removals: Arc<RemoteRemovals>,  // Shared with the other workers' wheels
+}
+
+impl Default for TimerWheel {
+    fn default() -> Self {
+        Self::new(DEFAULT_TICK, DEFAULT_SLOTS)
+    }
+}
+
+impl TimerWheel {
+    pub fn new(tick: Duration, slots: usize) -> Self {
+        assert!(slots > 0 && !tick.is_zero(), "timer wheel needs slots and a non-zero tick");
+
+        TimerWheel {
+            slots: (0..slots).map(|_| Vec::new()).collect(),
+            tick,
+            start: Instant::now(),
+            current_tick: 0,
+            len: 0,
+            worker: 0,
+            removals: Arc::new(RemoteRemovals::new(1)),
+        }
+    }
+
+    /// A wheel for worker `worker` of a MultiScheduler, whose wheels all
+    /// share `removals`
+    pub fn for_worker(worker: usize, removals: Arc<RemoteRemovals>) -> Self {
+        TimerWheel { worker, removals, ..Self::default() }
+    }
+
+    fn tick_of(&self, instant: Instant) -> u64 {
+        (instant.saturating_duration_since(self.start).as_nanos() / self.tick.as_nanos()) as u64
+    }
+
+    fn slot_of(&self, tick: u64) -> usize {
+        (tick % self.slots.len() as u64) as usize
+    }
+
+    /// Arms a timer that wakes `waker` once `deadline` has passed
+    pub fn insert(&mut self, deadline: Instant, waker: Waker) -> TimerId {
+        // Deadlines in already processed ticks go in the current slot,
+        // which is the next one to be looked at
+        let tick = self.tick_of(deadline).max(self.current_tick);
+        let slot = self.slot_of(tick);
+        let id = NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed);
+
+        self.slots[slot].push(TimerEntry { id, deadline, waker });
+        self.len += 1;
+        TimerId { slot, id, worker: self.worker }
+    }
+
+    /// Disarms a timer; does nothing if it already fired
+    /// A timer armed on another worker's wheel goes to that worker's
+    /// inbox, and is gone once the worker next advances its wheel.
+    pub fn remove(&mut self, timer: TimerId) {
+        if timer.worker != self.worker {
+            self.removals.inboxes[timer.worker].lock().unwrap().push(timer);
+            if let Some(notify) = &self.removals.notify {
+                notify(timer.worker);
+            }
+            return;
+        }
+
+        let slot = &mut self.slots[timer.slot];
+        if let Some(index) = slot.iter().position(|entry| entry.id == timer.id) {
+            slot.swap_remove(index);
+            self.len -= 1;
+        }
+    }
+
+    /// Fires every timer due at `now` and returns how many fired
+    pub fn advance(&mut self, now: Instant) -> usize {
+        let removed = mem::take(&mut *self.removals.inboxes[self.worker].lock().unwrap());
+        for timer in removed {
+            self.remove(timer);
+        }
+
+        let target = self.tick_of(now);
+        let mut fired = 0;
+
+        // After a long idle gap one full turn already visits every slot
+        let passed = (target.saturating_sub(self.current_tick)).min(self.slots.len() as u64);
+        for step in 0..passed {
+            fired += self.fire_slot(self.current_tick + step, now);
+        }
+        self.current_tick = self.current_tick.max(target);
+
+        // The current tick is only partly over: fire what's due, keep the rest
+        fired += self.fire_slot(self.current_tick, now);
+        fired
+    }
+
+    fn fire_slot(&mut self, tick: u64, now: Instant) -> usize {
+        let index = self.slot_of(tick);
+        let slot = &mut self.slots[index];
+        let before = slot.len();
+
+        slot.retain(|entry| {
+            if entry.deadline <= now {
+                entry.waker.wake_by_ref();
+                false
+            } else {
+                true
+            }
+        });
+
+        let fired = before - slot.len();
+        self.len -= fired;
+        fired
+    }
+
+    /// The earliest armed deadline, used to decide how long to idle
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.slots.iter().flatten().map(|entry| entry.deadline).min()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
@@ -0,0 +1,202 @@
+//! Scheduler tracing
+//! Every coroutine moves through the same few states: it waits in the ready
+//! queue, runs for a slice, then yields, blocks or finishes. The tracer is
+//! told about each transition and keeps:
+//! - per-coroutine counters (CoroutineStats), always on since they are cheap
+//! - optionally, a timeline of every slice and wait, which can be exported
+//!   as Chrome trace-event JSON and opened in chrome://tracing or Perfetto,
+//!   one row per coroutine
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use super::CoroutineState;
+
+/// What the scheduler measured for one coroutine (or future)
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoroutineStats {
+    pub id: usize,
+    pub spawned_at: Instant,
+    pub finished_at: Option<Instant>,  // None while it hasn't finished
+    pub resumes: u64,                  // Time slices it was given
+    pub run_time: Duration,            // Total time spent Running
+    pub suspended_time: Duration,      // Runnable but waiting in the ready queue
+    pub blocked_time: Duration,        // Parked until its Waker fired
+    state: Phase,                      // State since `since`, for the timeline
+    since: Instant,
+}
+
+/// A CoroutineState without its payload, cheap to keep and to copy
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    Ready,
+    Running,
+    Suspended,
+    Blocked,
+    Complete,
+    Panicked,
+    Cancelled,
+}
+
+impl From<&CoroutineState> for Phase {
+    fn from(state: &CoroutineState) -> Self {
+        match state {
+            CoroutineState::Ready => Phase::Ready,
+            CoroutineState::Running => Phase::Running,
+            CoroutineState::Suspended => Phase::Suspended,
+            CoroutineState::Blocked => Phase::Blocked,
+            CoroutineState::Complete => Phase::Complete,
+            CoroutineState::Panicked(_) => Phase::Panicked,
+            CoroutineState::Cancelled => Phase::Cancelled,
+        }
+    }
+}
+
+impl Phase {
+    /// Same as CoroutineState::name()
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Ready => "Ready",
+            Phase::Running => "Running",
+            Phase::Suspended => "Suspended",
+            Phase::Blocked => "Blocked",
+            Phase::Complete => "Complete",
+            Phase::Panicked => "Panicked",
+            Phase::Cancelled => "Cancelled",
+        }
+    }
+}
+
+/// One span on a coroutine's row of the timeline
+struct Span {
+    id: usize,
+    state: Phase,
+    start: Instant,
+    end: Instant,
+}
+
+pub struct Tracer {
+    epoch: Instant,  // Time zero of the exported timeline
+    stats: HashMap<usize, CoroutineStats>,
+    timeline: Option<Vec<Span>>,  // Only recorded when tracing is on
+}
+
+impl Tracer {
+    pub fn new(record_timeline: bool) -> Self {
+        Tracer {
+            epoch: Instant::now(),
+            stats: HashMap::new(),
+            timeline: record_timeline.then(Vec::new),
+        }
+    }
+
+    pub fn spawned(&mut self, id: usize) {
+        let now = Instant::now();
+        self.stats.insert(
+            id,
+            CoroutineStats {
+                id,
+                spawned_at: now,
+                finished_at: None,
+                resumes: 0,
+                run_time: Duration::ZERO,
+                suspended_time: Duration::ZERO,
+                blocked_time: Duration::ZERO,
+                state: Phase::Ready,
+                since: now,
+            },
+        );
+    }
+
+    /// The coroutine is about to run
+    pub fn resumed(&mut self, id: usize, at: Instant) {
+        self.transition(id, Phase::Running, at);
+        if let Some(stats) = self.stats.get_mut(&id) {
+            stats.resumes += 1;
+        }
+    }
+
+    /// The coroutine gave control back and is now in `state`
+    pub fn stopped(&mut self, id: usize, state: &CoroutineState, at: Instant) {
+        self.transition(id, state.into(), at);
+        if let Some(stats) = self.stats.get_mut(&id) {
+            if state.is_finished() {
+                stats.finished_at = Some(at);
+            }
+        }
+    }
+
+    /// A blocked coroutine was moved back to the ready queue
+    pub fn woken(&mut self, id: usize, at: Instant) {
+        self.transition(id, Phase::Ready, at);
+    }
+
+    /// Closes the span of the state the coroutine was in and opens the next
+    fn transition(&mut self, id: usize, state: Phase, at: Instant) {
+        let Some(stats) = self.stats.get_mut(&id) else {
+            return;
+        };
+
+        let spent = at.saturating_duration_since(stats.since);
+        match stats.state {
+            Phase::Running => stats.run_time += spent,
+            Phase::Blocked => stats.blocked_time += spent,
+            Phase::Ready | Phase::Suspended => stats.suspended_time += spent,
+            // Nothing comes after these
+            Phase::Complete | Phase::Panicked | Phase::Cancelled => {}
+        }
+
+        if let Some(timeline) = &mut self.timeline {
+            timeline.push(Span { id, state: stats.state, start: stats.since, end: at });
+        }
+        stats.state = state;
+        stats.since = at;
+    }
+
+    /// Statistics of every coroutine seen so far, by id
+    pub fn stats(&self) -> Vec<CoroutineStats> {
+        let mut stats: Vec<_> = self.stats.values().cloned().collect();
+        stats.sort_by_key(|stats| stats.id);
+        stats
+    }
+
+    /// Writes the timeline as Chrome trace-event JSON
+    /// Each coroutine is shown as a thread; every Running, Ready, Suspended
+    /// and Blocked period is a complete ("X") event on its row, and the
+    /// end of a coroutine is an instant ("i") event.
+    pub fn write_chrome_trace(&self, mut out: impl Write) -> io::Result<()> {
+        let micros = |at: Instant| at.saturating_duration_since(self.epoch).as_secs_f64() * 1e6;
+        let mut events = Vec::new();
+
+        for stats in self.stats() {
+            events.push(format!(
+                r#"{{"name":"thread_name","ph":"M","pid":1,"tid":{},"args":{{"name":"coroutine {}"}}}}"#,
+                stats.id, stats.id
+            ));
+            if let Some(finished) = stats.finished_at {
+                events.push(format!(
+                    r#"{{"name":"{}","ph":"i","s":"t","pid":1,"tid":{},"ts":{:.3}}}"#,
+                    stats.state.name(), stats.id, micros(finished)
+                ));
+            }
+        }
+
+        for span in self.timeline.iter().flatten() {
+            events.push(format!(
+                r#"{{"name":"{}","cat":"coroutine","ph":"X","pid":1,"tid":{},"ts":{:.3},"dur":{:.3}}}"#,
+                span.state.name(),
+                span.id,
+                micros(span.start),
+                micros(span.end) - micros(span.start)
+            ));
+        }
+
+        writeln!(out, "{{\"traceEvents\":[")?;
+        for (index, event) in events.iter().enumerate() {
+            let separator = if index + 1 < events.len() { "," } else { "" };
+            writeln!(out, "{}{}", event, separator)?;
+        }
+        writeln!(out, "]}}")
+    }
+}
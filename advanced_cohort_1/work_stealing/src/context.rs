@@ -0,0 +1,212 @@
+//! Worker context: fork/join from inside a running task
+//! While a worker runs, a thread-local points at it, so code deep inside a
+//! task can add work to that worker's own deque without being handed
+//! anything:
+//! - spawn(): pushes a new task; its result comes back through a handle
+//! - join(a, b): pushes `b`, runs `a` right away, then pops `b` back and
+//!   runs it too, unless an idle worker stole it in the meantime
+//!
+//! Recursive divide-and-conquer is where the deque's design pays off. The
+//! owner pops the newest, smallest pieces (still warm in its cache), while
+//! thieves take the oldest ones from the other end, which near the root
+//! of the recursion are the biggest.
+
+use std::cell::Cell;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::ptr;
+use std::sync::atomic::Ordering;
+use std::thread;
+
+use super::{Task, TaskHandle, Worker};
+
+thread_local! {
+    /// The worker running on this thread, if any
+    static CURRENT: Cell<*const Worker> = const { Cell::new(ptr::null()) };
+}
+
+/// Makes `worker` the current one; returns the previous one for leave()
+pub(crate) fn enter(worker: &Worker) -> *const Worker {
+    CURRENT.with(|current| current.replace(worker))
+}
+
+pub(crate) fn leave(previous: *const Worker) {
+    CURRENT.with(|current| current.set(previous));
+}
+
+/// Calls `f` with the worker running on this thread
+fn with_current<R>(f: impl FnOnce(&Worker) -> R) -> Option<R> {
+    let worker = CURRENT.with(Cell::get);
+    // SAFETY: set by Worker::run() for exactly as long as it borrows the worker
+    unsafe { worker.as_ref() }.map(f)
+}
+
+/// ID of the worker running the calling task, None outside of one
+pub fn current_worker_id() -> Option<usize> {
+    with_current(|worker| worker.id)
+}
+
+/// Adds a task to the current worker's deque
+/// The scheduler's run() doesn't return before it has run. Joining the
+/// handle from inside another task blocks that worker's thread meanwhile,
+/// so prefer join() there, or hand the handle out of the task.
+///
+/// Panics when called outside of a task.
+pub fn spawn<F, T>(func: F) -> TaskHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    with_current(|worker| {
+        let (task, handle) = Task::new(worker.next_task_id(), 0, func);
+        worker.push_task(task);
+        handle
+    })
+    .expect("spawn() called outside of a worker")
+}
+
+/// Runs `a` and `b`, potentially in parallel, and returns both results
+/// Like rayon::join, both closures may borrow from the caller. Outside of
+/// a worker they just run one after the other. If either one panics, the
+/// panic carries on once both are done.
+pub fn join<A, B, RA, RB>(a: A, b: B) -> (RA, RB)
+where
+    A: FnOnce() -> RA + Send,
+    B: FnOnce() -> RB + Send,
+    RA: Send,
+    RB: Send,
+{
+    let worker = CURRENT.with(Cell::get);
+    // SAFETY: see with_current()
+    let Some(worker) = (unsafe { worker.as_ref() }) else {
+        return (a(), b());
+    };
+
+    // SAFETY: we don't return, not even by unwinding, before `b` has run:
+    // a panic in `a` is caught, and one out of wait_for() aborts
+    let (task, handle) = unsafe { Task::new_unchecked(worker.next_task_id(), 0, b) };
+    let b_id = task.id;
+    worker.push_task(task);
+
+    let result_a = panic::catch_unwind(AssertUnwindSafe(a));
+    let abort = AbortOnUnwind;
+    wait_for(worker, b_id, &handle);
+    mem::forget(abort);
+
+    match (result_a, handle.join()) {
+        (Ok(result_a), Ok(result_b)) => (result_a, result_b),
+        (Err(payload), _) | (_, Err(payload)) => panic::resume_unwind(payload),
+    }
+}
+
+/// Aborts the process if it is dropped, which only happens by unwinding;
+/// forget it once the danger is over
+/// Tasks catch their own panics, but wait_for() may still panic in its own
+/// code (a println! on a closed stdout, a poisoned lock). Unwinding out of
+/// join() then would free the stack that a thief running `b` still
+/// borrows from, so, like rayon, we'd rather not go on at all.
+struct AbortOnUnwind;
+
+impl Drop for AbortOnUnwind {
+    fn drop(&mut self) {
+        eprintln!("panicked while waiting for the other side of join(), aborting");
+        process::abort();
+    }
+}
+
+/// Runs the task `b_id` if it is still ours, otherwise makes ourselves
+/// useful until whoever stole it is done
+fn wait_for<T>(worker: &Worker, b_id: usize, handle: &TaskHandle<T>) {
+    while !handle.is_finished() {
+        match worker.local_queue.pop() {
+            // Nobody wanted it: run it right here, like a plain call
+            Some(task) if task.id == b_id => {
+                (task.job)();
+                worker.tasks_completed.fetch_add(1, Ordering::SeqCst);
+            }
+            // Spawned by `a` and not run yet; it sits above `b`
+            Some(task) => worker.execute_task(task),
+            // `b` was stolen
+            None => {
+                if !worker.steal_and_execute() {
+                    thread::yield_now();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::WorkStealingScheduler;
+    use std::sync::atomic::AtomicBool;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn join_matches_the_sequential_result() {
+        fn sum(items: &[u64]) -> u64 {
+            if items.len() <= 16 {
+                return items.iter().sum();
+            }
+            let (left, right) = items.split_at(items.len() / 2);
+            let (left, right) = join(|| sum(left), || sum(right));
+            left + right
+        }
+
+        let mut scheduler = WorkStealingScheduler::new(4);
+        let handle = scheduler.add_task(0, 0, || sum(&(1..=10_000).collect::<Vec<_>>()));
+        scheduler.run();
+        assert_eq!(handle.join().unwrap(), 10_000 * 10_001 / 2);
+    }
+
+    /// `b` borrows from join()'s caller, so a panic in `a` must not
+    /// unwind past join() before `b` is done
+    #[test]
+    fn join_waits_for_both_sides_when_one_panics() {
+        let mut scheduler = WorkStealingScheduler::new(2);
+        let handle = scheduler.add_task(0, 0, || {
+            let b_finished = AtomicBool::new(false);
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                join(
+                    || panic!("a failed"),
+                    || {
+                        thread::sleep(Duration::from_millis(20));
+                        b_finished.store(true, Ordering::SeqCst);
+                    },
+                )
+            }));
+            (result.is_err(), b_finished.load(Ordering::SeqCst))
+        });
+        scheduler.run();
+        assert_eq!(handle.join().unwrap(), (true, true));
+    }
+
+    #[test]
+    fn an_idle_worker_steals_b_while_a_runs() {
+        let mut scheduler = WorkStealingScheduler::new(2);
+        let handle = scheduler.add_task(0, 0, || {
+            let b_started = AtomicBool::new(false);
+            join(
+                || {
+                    // Only returns early if someone else got to `b`
+                    let waiting = Instant::now();
+                    while !b_started.load(Ordering::SeqCst) && waiting.elapsed() < Duration::from_secs(5) {
+                        thread::yield_now();
+                    }
+                    (current_worker_id(), b_started.load(Ordering::SeqCst))
+                },
+                || {
+                    b_started.store(true, Ordering::SeqCst);
+                    current_worker_id()
+                },
+            )
+        });
+        scheduler.run();
+
+        let ((a_worker, b_ran_meanwhile), b_worker) = handle.join().unwrap();
+        assert!(b_ran_meanwhile);
+        assert_eq!((a_worker, b_worker), (Some(0), Some(1)));
+    }
+}
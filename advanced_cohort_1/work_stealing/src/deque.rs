@@ -0,0 +1,507 @@
+//! Lock-free work-stealing deque (Chase-Lev)
+//! The owner pushes and pops at the bottom, thieves steal from the top,
+//! and nobody ever takes a lock:
+//! 1. Two indices, `top` and `bottom`, delimit the tasks in a circular buffer
+//! 2. The owner is the only one who moves `bottom`, so push never races
+//! 3. Thieves claim the task at `top` with a compare-and-swap; losing it
+//!    just means another thief (or the owner) got there first
+//! 4. The owner and a thief only compete for the very last task, and settle
+//!    it with the same compare-and-swap on `top`
+//! 5. A full buffer is replaced by one twice the size. Thieves may still be
+//!    reading the old one, so it is kept until the deque itself goes away
+//!
+//! The memory orderings follow "Correct and Efficient Work-Stealing for
+//! Weak Memory Models" (Lê, Pop, Cohen, Zappa Nardelli, PPoPP 2013).
+//!
+//! The ownership rule is enforced by the types: WorkStealingDeque is the
+//! owner's end and can't be shared between threads, Stealer is the thieves'
+//! end and can be cloned freely.
+//!
+//! Besides the stress tests, the races are model-checked with loom:
+//!     RUSTFLAGS="--cfg loom" cargo test --release deque
+
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::ptr;
+
+#[cfg(loom)]
+use loom::sync::atomic::{fence, AtomicIsize, AtomicPtr, Ordering};
+#[cfg(loom)]
+use loom::sync::Arc;
+#[cfg(not(loom))]
+use std::sync::atomic::{fence, AtomicIsize, AtomicPtr, Ordering};
+#[cfg(not(loom))]
+use std::sync::Arc;
+
+/// Slots in a new deque's buffer
+const MIN_CAPACITY: usize = 32;
+
+/// Fixed-size circular buffer; indices wrap around with a mask
+struct Buffer<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+}
+
+impl<T> Buffer<T> {
+    /// `capacity` must be a power of two
+    fn alloc(capacity: usize) -> *mut Buffer<T> {
+        debug_assert!(capacity.is_power_of_two());
+        let slots = (0..capacity).map(|_| UnsafeCell::new(MaybeUninit::uninit())).collect();
+        Box::into_raw(Box::new(Buffer { slots }))
+    }
+
+    fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn slot(&self, index: isize) -> *mut MaybeUninit<T> {
+        self.slots[index as usize & (self.capacity() - 1)].get()
+    }
+
+    /// Only the owner writes, and only to slots no thief can claim yet
+    unsafe fn write(&self, index: isize, value: T) {
+        ptr::write_volatile(self.slot(index), MaybeUninit::new(value));
+    }
+
+    /// Reads may race with the owner reusing the slot after a wrap-around;
+    /// such a read loses the compare-and-swap on `top` and is thrown away,
+    /// which is why the value stays MaybeUninit until then
+    unsafe fn read(&self, index: isize) -> MaybeUninit<T> {
+        ptr::read_volatile(self.slot(index))
+    }
+}
+
+/// State shared by the owner and every thief
+struct Inner<T> {
+    top: AtomicIsize,     // Next task a thief takes
+    bottom: AtomicIsize,  // Next free slot for the owner
+    buffer: AtomicPtr<Buffer<T>>,
+    retired: UnsafeCell<Vec<*mut Buffer<T>>>,  // Outgrown buffers; only the owner touches these
+}
+
+// SAFETY: tasks move between threads (T: Send), `retired` is only ever
+// touched by the single owner, and everything else is atomic
+unsafe impl<T: Send> Send for Inner<T> {}
+unsafe impl<T: Send> Sync for Inner<T> {}
+
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        let top = self.top.load(Ordering::Relaxed);
+        let bottom = self.bottom.load(Ordering::Relaxed);
+        let buffer = self.buffer.load(Ordering::Relaxed);
+
+        unsafe {
+            // Tasks nobody got to are still owned by the deque
+            for index in top..bottom {
+                (*buffer).read(index).assume_init_drop();
+            }
+            // Outgrown buffers only hold stale copies, nothing to drop
+            drop(Box::from_raw(buffer));
+            for old in self.retired.get_mut().drain(..) {
+                drop(Box::from_raw(old));
+            }
+        }
+    }
+}
+
+/// The owner's end: LIFO push and pop at the bottom
+/// Can be moved to another thread but not shared; see stealer() for the
+/// handles other threads use.
+pub struct WorkStealingDeque<T> {
+    inner: Arc<Inner<T>>,
+    _not_sync: PhantomData<*mut ()>,
+}
+
+// SAFETY: whoever holds the owner's end is the owner, on whatever thread
+unsafe impl<T: Send> Send for WorkStealingDeque<T> {}
+
+/// The thieves' end: FIFO steals from the top
+pub struct Stealer<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Clone for Stealer<T> {
+    fn clone(&self) -> Self {
+        Stealer { inner: self.inner.clone() }
+    }
+}
+
+impl<T> WorkStealingDeque<T> {
+    pub fn new() -> Self {
+        Self::with_capacity(MIN_CAPACITY)
+    }
+
+    /// Starts with room for `capacity` tasks (rounded up to a power of two);
+    /// it grows as needed either way
+    pub fn with_capacity(capacity: usize) -> Self {
+        let capacity = capacity.max(1).next_power_of_two();
+        WorkStealingDeque {
+            inner: Arc::new(Inner {
+                top: AtomicIsize::new(0),
+                bottom: AtomicIsize::new(0),
+                buffer: AtomicPtr::new(Buffer::alloc(capacity)),
+                retired: UnsafeCell::new(Vec::new()),
+            }),
+            _not_sync: PhantomData,
+        }
+    }
+
+    /// A handle other threads can steal through
+    pub fn stealer(&self) -> Stealer<T> {
+        Stealer { inner: self.inner.clone() }
+    }
+
+    /// Push task to the back (used by owner)
+    pub fn push(&self, task: T) {
+        let inner = &*self.inner;
+        let bottom = inner.bottom.load(Ordering::Relaxed);
+        let top = inner.top.load(Ordering::Acquire);
+        let mut buffer = inner.buffer.load(Ordering::Relaxed);
+
+        unsafe {
+            if bottom - top >= (*buffer).capacity() as isize {
+                buffer = self.grow(buffer, top, bottom);
+            }
+            (*buffer).write(bottom, task);
+        }
+
+        // Thieves that see the new bottom must also see the task
+        fence(Ordering::Release);
+        inner.bottom.store(bottom + 1, Ordering::Relaxed);
+    }
+
+    /// Pop task from the back (used by owner)
+    pub fn pop(&self) -> Option<T> {
+        let inner = &*self.inner;
+        let bottom = inner.bottom.load(Ordering::Relaxed) - 1;
+        let buffer = inner.buffer.load(Ordering::Relaxed);
+
+        // Reserve the last task before looking at `top`; the SeqCst fence
+        // pairs with the one in steal() so that the two of us can't both
+        // believe we got it
+        inner.bottom.store(bottom, Ordering::Relaxed);
+        fence(Ordering::SeqCst);
+        let top = inner.top.load(Ordering::Relaxed);
+
+        if top > bottom {
+            // Empty: undo the reservation
+            inner.bottom.store(bottom + 1, Ordering::Relaxed);
+            return None;
+        }
+
+        let task = unsafe { (*buffer).read(bottom) };
+        if top < bottom {
+            // More than one task left, no thief can reach this one
+            return Some(unsafe { task.assume_init() });
+        }
+
+        // The last task: race the thieves for it
+        let won = inner
+            .top
+            .compare_exchange(top, top + 1, Ordering::SeqCst, Ordering::Relaxed)
+            .is_ok();
+        inner.bottom.store(bottom + 1, Ordering::Relaxed);
+        won.then(|| unsafe { task.assume_init() })
+    }
+
+    /// Tasks in the deque
+    pub fn size(&self) -> usize {
+        self.inner.size()
+    }
+
+    /// Moves the tasks into a buffer twice as big
+    /// The old buffer stays around (read-only) for thieves still using it.
+    unsafe fn grow(&self, old: *mut Buffer<T>, top: isize, bottom: isize) -> *mut Buffer<T> {
+        let new = Buffer::alloc((*old).capacity() * 2);
+        for index in top..bottom {
+            ptr::copy_nonoverlapping((*old).slot(index), (*new).slot(index), 1);
+        }
+
+        self.inner.buffer.store(new, Ordering::Release);
+        (*self.inner.retired.get()).push(old);
+        new
+    }
+}
+
+impl<T> Default for WorkStealingDeque<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Stealer<T> {
+    /// Steal task from the front (used by thieves)
+    /// Only gives up once the deque is empty: losing a race to another
+    /// thief means that one made progress, so trying again is lock-free.
+    pub fn steal(&self) -> Option<T> {
+        loop {
+            match self.inner.steal() {
+                Steal::Success(task) => return Some(task),
+                Steal::Empty => return None,
+                Steal::Retry => continue,
+            }
+        }
+    }
+
+    /// Steals half of the tasks (rounded up), oldest first; empty if there
+    /// were none
+    /// Taking them one at a time keeps each steal as safe as steal(), but
+    /// the owner and other thieves may get some of them in between.
+    pub fn steal_half(&self) -> Vec<T> {
+        let half = self.size().div_ceil(2).max(1);
+        let mut stolen = Vec::with_capacity(half);
+        while stolen.len() < half {
+            match self.steal() {
+                Some(task) => stolen.push(task),
+                None => break,
+            }
+        }
+        stolen
+    }
+
+    /// Tasks in the deque
+    pub fn size(&self) -> usize {
+        self.inner.size()
+    }
+}
+
+/// Outcome of a single steal attempt
+enum Steal<T> {
+    Success(T),
+    Empty,
+    Retry,  // Someone else took the task at `top` first
+}
+
+impl<T> Inner<T> {
+    fn steal(&self) -> Steal<T> {
+        let top = self.top.load(Ordering::Acquire);
+        fence(Ordering::SeqCst);
+        let bottom = self.bottom.load(Ordering::Acquire);
+
+        if top >= bottom {
+            return Steal::Empty;
+        }
+
+        // Read before claiming: once `top` moves on, the owner may reuse the slot
+        let buffer = self.buffer.load(Ordering::Acquire);
+        let task = unsafe { (*buffer).read(top) };
+
+        match self.top.compare_exchange(top, top + 1, Ordering::SeqCst, Ordering::Relaxed) {
+            Ok(_) => Steal::Success(unsafe { task.assume_init() }),
+            Err(_) => Steal::Retry,
+        }
+    }
+
+    /// A snapshot, exact only while nobody pushes, pops or steals; a pop
+    /// in progress briefly counts as done
+    fn size(&self) -> usize {
+        let bottom = self.bottom.load(Ordering::Relaxed);
+        let top = self.top.load(Ordering::Relaxed);
+        (bottom - top).max(0) as usize
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    #[test]
+    fn owner_pops_newest_and_thieves_steal_oldest() {
+        let deque = WorkStealingDeque::new();
+        let stealer = deque.stealer();
+        for task in 1..=4 {
+            deque.push(task);
+        }
+
+        assert_eq!(stealer.steal(), Some(1));
+        assert_eq!(deque.pop(), Some(4));
+        assert_eq!(stealer.steal(), Some(2));
+        assert_eq!(deque.size(), 1);
+        assert_eq!(deque.pop(), Some(3));
+        assert_eq!(deque.pop(), None);
+        assert_eq!(stealer.steal(), None);
+        assert_eq!(stealer.size(), 0);
+    }
+
+    #[test]
+    fn steal_half_takes_the_oldest_half() {
+        let deque = WorkStealingDeque::new();
+        let stealer = deque.stealer();
+        for task in 0..5 {
+            deque.push(task);
+        }
+
+        assert_eq!(stealer.steal_half(), [0, 1, 2]);
+        assert_eq!(stealer.steal_half(), [3]);
+        assert_eq!(stealer.steal_half(), [4]);
+        assert_eq!(stealer.steal_half(), []);
+    }
+
+    #[test]
+    fn grows_past_its_initial_capacity() {
+        let deque = WorkStealingDeque::with_capacity(2);
+        let stealer = deque.stealer();
+        for task in 0..1000 {
+            deque.push(task);
+        }
+        assert_eq!(deque.size(), 1000);
+
+        assert_eq!(stealer.steal(), Some(0));
+        for task in (1..1000).rev() {
+            assert_eq!(deque.pop(), Some(task));
+        }
+        assert_eq!(deque.pop(), None);
+    }
+
+    #[test]
+    fn drops_the_tasks_nobody_took() {
+        let task = Arc::new(());
+        let deque = WorkStealingDeque::with_capacity(2);
+        for _ in 0..10 {
+            deque.push(task.clone());
+        }
+        deque.pop();
+        deque.stealer().steal();
+
+        drop(deque);
+        assert_eq!(Arc::strong_count(&task), 1);
+    }
+
+    /// The owner pushes and pops while thieves steal, starting from a tiny
+    /// buffer so that it grows under their feet; every task must come out
+    /// exactly once
+    #[test]
+    fn no_task_is_lost_or_duplicated_under_contention() {
+        const TASKS: usize = 200_000;
+        const THIEVES: usize = 4;
+
+        let deque = WorkStealingDeque::with_capacity(2);
+        let done = Arc::new(AtomicBool::new(false));
+
+        // Half of the thieves steal one task at a time, half in batches
+        let thieves: Vec<_> = (0..THIEVES)
+            .map(|thief| {
+                let stealer = deque.stealer();
+                let done = done.clone();
+                thread::spawn(move || {
+                    let mut stolen = Vec::new();
+                    // Checking `done` first: once it's set, one more empty
+                    // steal means there is nothing left for good
+                    loop {
+                        let finished = done.load(Ordering::Acquire);
+                        let before = stolen.len();
+                        if thief % 2 == 0 {
+                            stolen.extend(stealer.steal());
+                        } else {
+                            stolen.extend(stealer.steal_half());
+                        }
+                        if stolen.len() == before {
+                            if finished {
+                                break;
+                            }
+                            thread::yield_now();
+                        }
+                    }
+                    stolen
+                })
+            })
+            .collect();
+
+        let mut popped = Vec::new();
+        for task in 0..TASKS {
+            deque.push(task);
+            if task % 3 == 0 {
+                popped.extend(deque.pop());
+            }
+        }
+        while let Some(task) = deque.pop() {
+            popped.push(task);
+        }
+        done.store(true, Ordering::Release);
+
+        let mut seen = HashSet::new();
+        let stolen: Vec<usize> = thieves.into_iter().flat_map(|t| t.join().unwrap()).collect();
+        for task in popped.iter().chain(&stolen) {
+            assert!(seen.insert(*task), "task {} came out twice", task);
+        }
+        assert_eq!(seen.len(), TASKS, "tasks were lost");
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use loom::thread;
+
+    /// Runs every interleaving; each task must come out exactly once
+    fn check(outcomes: Vec<Vec<usize>>, tasks: usize) {
+        let mut all: Vec<usize> = outcomes.into_iter().flatten().collect();
+        all.sort_unstable();
+        assert_eq!(all, (0..tasks).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn pop_and_steal_race_for_the_last_task() {
+        loom::model(|| {
+            let deque = WorkStealingDeque::with_capacity(2);
+            let stealer = deque.stealer();
+            deque.push(0);
+
+            let thief = thread::spawn(move || stealer.steal().into_iter().collect());
+            let popped = deque.pop().into_iter().collect();
+
+            check(vec![popped, thief.join().unwrap()], 1);
+        });
+    }
+
+    #[test]
+    fn steal_while_the_buffer_grows() {
+        loom::model(|| {
+            let deque = WorkStealingDeque::with_capacity(2);
+            let stealer = deque.stealer();
+            deque.push(0);
+            deque.push(1);
+
+            let thief = thread::spawn(move || {
+                let mut stolen = Vec::new();
+                stolen.extend(stealer.steal());
+                stolen.extend(stealer.steal());
+                stolen
+            });
+            deque.push(2);  // Full: grows while the thief may be reading
+
+            let mut popped = Vec::new();
+            while let Some(task) = deque.pop() {
+                popped.push(task);
+            }
+            check(vec![popped, thief.join().unwrap()], 3);
+        });
+    }
+
+    #[test]
+    fn two_thieves_and_the_owner() {
+        loom::model(|| {
+            let deque = WorkStealingDeque::with_capacity(2);
+            deque.push(0);
+            deque.push(1);
+
+            let thieves: Vec<_> = (0..2)
+                .map(|_| {
+                    let stealer = deque.stealer();
+                    thread::spawn(move || stealer.steal().into_iter().collect())
+                })
+                .collect();
+            let popped = deque.pop().into_iter().collect();
+
+            let mut outcomes = vec![popped];
+            outcomes.extend(thieves.into_iter().map(|t| t.join().unwrap()));
+            // One of the tasks may be left over when the owner popped first
+            // and both thieves raced for the other; drain it
+            outcomes.push(deque.pop().into_iter().collect());
+            check(outcomes, 2);
+        });
+    }
+}
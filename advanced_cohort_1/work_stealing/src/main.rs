@@ -1,110 +1,56 @@
 //! Work Stealing Implementation for Coroutines
 //! Demonstrates advanced scheduling concepts with work stealing
 
-use std::sync::{Arc, Mutex};
-use std::collections::VecDeque;
-use std::time::Duration;
+mod context;
+mod deque;
+mod steal;
+mod task;
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::thread;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
-/// Represents a task that can be executed by our coroutines
-#[derive(Debug)]
-struct Task {
-    id: usize,
-    priority: usize,
-    work_units: usize,
-    state: TaskState,
-}
-
-#[derive(Debug, Clone, Copy, PartialEq)]
-enum TaskState {
-    Ready,
-    Running,
-    Completed,
-    Stolen,
-}
-
-/// A deque that supports both LIFO and FIFO operations
-/// This is crucial for work stealing as workers use it differently:
-/// - Owner uses it as a LIFO stack (push/pop from back)
-/// - Thieves use it as a FIFO queue (steal from front)
-struct WorkStealingDeque {
-    tasks: Arc<Mutex<VecDeque<Task>>>,
-    size: Arc<AtomicUsize>,
-}
+use deque::{Stealer, WorkStealingDeque};
+use context::{join, spawn};
+use steal::{LargestQueueFirst, LocalityAware, RandomVictim, RoundRobin, StealPolicy};
+use task::{Task, TaskHandle, TaskState};
 
-impl WorkStealingDeque {
-    fn new() -> Self {
-        WorkStealingDeque {
-            tasks: Arc::new(Mutex::new(VecDeque::new())),
-            size: Arc::new(AtomicUsize::new(0)),
-        }
-    }
-
-    /// Push task to the back (used by owner)
-    fn push(&self, task: Task) {
-        let mut queue = self.tasks.lock().unwrap();
-        queue.push_back(task);
-        self.size.fetch_add(1, Ordering::SeqCst);
-    }
-
-    /// Pop task from the back (used by owner)
-    fn pop(&self) -> Option<Task> {
-        let mut queue = self.tasks.lock().unwrap();
-        let task = queue.pop_back();
-        if task.is_some() {
-            self.size.fetch_sub(1, Ordering::SeqCst);
-        }
-        task
-    }
-
-    /// Steal task from the front (used by thieves)
-    fn steal(&self) -> Option<Task> {
-        let mut queue = self.tasks.lock().unwrap();
-        let task = queue.pop_front();
-        if task.is_some() {
-            self.size.fetch_sub(1, Ordering::SeqCst);
-        }
-        task
-    }
-
-    fn size(&self) -> usize {
-        self.size.load(Ordering::SeqCst)
-    }
-}
+/// How long a worker that found nothing to run or steal waits before it
+/// looks again
+/// Short enough not to show up in the timings of demo_steal_policies,
+/// which would otherwise measure mostly this.
+const IDLE_BACKOFF: Duration = Duration::from_millis(1);
 
 /// Represents a worker in our work stealing scheduler
-#[derive(Clone)]
+/// It owns its deque; the others only hold stealers for it.
 struct Worker {
     id: usize,
-    local_queue: Arc<WorkStealingDeque>,
-    other_queues: Vec<Arc<WorkStealingDeque>>,
+    local_queue: WorkStealingDeque<Task>,
+    queues: Vec<Stealer<Task>>,  // Every worker's queue, ours included, by worker id
+    steal: StealConfig,
+    producer_only: bool,  // Never steals, only feeds the others
     tasks_completed: Arc<AtomicUsize>,
     tasks_stolen: Arc<AtomicUsize>,
     total_system_tasks: Arc<AtomicUsize>,
+    next_task_id: Arc<AtomicUsize>,
+}
+
+/// How workers steal; the same for all workers of a scheduler
+#[derive(Clone)]
+struct StealConfig {
+    policy: Arc<dyn StealPolicy>,
+    min_imbalance: usize,
+    steal_half: bool,
 }
 
 impl Worker {
-    fn new(
-        id: usize,
-        other_queues: Vec<Arc<WorkStealingDeque>>,
-        tasks_completed: Arc<AtomicUsize>,
-        tasks_stolen: Arc<AtomicUsize>,
-        total_system_tasks: Arc<AtomicUsize>,
-    ) -> Self {
-        Worker {
-            id,
-            local_queue: Arc::new(WorkStealingDeque::new()),
-            other_queues,
-            tasks_completed,
-            tasks_stolen,
-            total_system_tasks,
-        }
-    }
     fn run(&self) {
         println!("\x1b[33mWorker {} starting with {} tasks. Number of other queues: {}\x1b[0m", 
-            self.id, self.local_queue.size(), self.other_queues.len());
+            self.id, self.local_queue.size(), self.queues.len() - 1);
         
+        // Lets the tasks we run spawn onto our queue
+        let previous = context::enter(self);
         loop {
             // First check if we should exit
             if self.all_work_complete() {
@@ -115,109 +61,134 @@ impl Worker {
                 break;
             }
 
-            // For non-zero workers, try to steal if they have no local work
-            if self.id != 0 && self.local_queue.size() == 0 {
-                if let Some(mut stolen_task) = self.steal_task() {
-                    println!("\x1b[32mWorker {} successfully stole task {}\x1b[0m", 
-                        self.id, stolen_task.id);
-                    stolen_task.state = TaskState::Stolen;
-                    self.tasks_stolen.fetch_add(1, Ordering::SeqCst);
-                    self.execute_task(&mut stolen_task);
-                    continue;
-                }
-            }
-    
             // Check local queue
-            if let Some(mut task) = self.local_queue.pop() {
-                self.execute_task(&mut task);
+            if let Some(task) = self.local_queue.pop() {
+                self.execute_task(task);
+                continue;
+            }
+
+            // Out of local work: help whoever has some left
+            if self.steal_and_execute() {
                 continue;
             }
     
             // If we get here, we couldn't find work - sleep briefly before trying again
-            thread::sleep(Duration::from_millis(50));
+            thread::sleep(IDLE_BACKOFF);
         }
+        context::leave(previous);
+    }
+
+    /// Steals a task and runs it; false if there was nothing to steal
+    fn steal_and_execute(&self) -> bool {
+        let Some(mut stolen_task) = self.steal_task() else {
+            return false;
+        };
+        println!("\x1b[32mWorker {} successfully stole task {}\x1b[0m", 
+            self.id, stolen_task.id);
+        stolen_task.state = TaskState::Stolen;
+        self.tasks_stolen.fetch_add(1, Ordering::SeqCst);
+        self.execute_task(stolen_task);
+        true
+    }
+
+    fn next_task_id(&self) -> usize {
+        self.next_task_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Queues a task on our own deque; counted right away, so that nobody
+    /// quits while it is pending
+    fn push_task(&self, task: Task) {
+        self.total_system_tasks.fetch_add(1, Ordering::SeqCst);
+        self.local_queue.push(task);
     }
 
     fn steal_task(&self) -> Option<Task> {
-        // Worker 0 never steals
-        if self.id == 0 {
+        if self.producer_only {
             return None;
         }
     
-        // Calculate minimum tasks to consider stealing
-        // Only steal if the source queue has at least this many more tasks
-        let min_imbalance = 0; // Only steal if queue has 10+ more tasks than us
-    
-        // For all other workers, try to steal from worker 0's queue first
-        if let Some(worker_0_queue) = self.other_queues.first() {
-            let source_size = worker_0_queue.size();
-            let our_size = self.local_queue.size();
-            
+        // The policy picks the order, the sizes it sees are a snapshot
+        let sizes: Vec<usize> = self.queues.iter().map(Stealer::size).collect();
+        let our_size = self.local_queue.size();
+
+        for victim in self.steal.policy.victims(self.id, &sizes) {
+            let source_size = sizes[victim];
+
             // Only steal if there's a significant imbalance
-            if source_size > our_size + min_imbalance {
-                let stolen = worker_0_queue.steal();
-                if stolen.is_some() {
-                    println!("Worker {} successfully stole from worker 0 (imbalance: {})", 
-                        self.id, source_size - our_size);
-                    return stolen;
-                }
+            if victim == self.id || source_size <= our_size + self.steal.min_imbalance {
+                continue;
             }
-        }
-    
-        // If we couldn't steal from worker 0, try other queues with same imbalance check
-        for (i, queue) in self.other_queues.iter().enumerate().skip(1) {
-            let source_size = queue.size();
-            let our_size = self.local_queue.size();
-            
-            if source_size > our_size + min_imbalance {
-                println!("Worker {} checking other queue {}, size: {}", self.id, i, source_size);
-                let stolen = queue.steal();
-                if stolen.is_some() {
-                    println!("Worker {} successfully stole from queue {} (imbalance: {})", 
-                        self.id, i, source_size - our_size);
-                    return stolen;
-                }
+
+            let stolen = if self.steal.steal_half {
+                self.steal_half_from(victim)
+            } else {
+                self.queues[victim].steal()
+            };
+            if stolen.is_some() {
+                println!("Worker {} successfully stole from worker {} (imbalance: {})", 
+                    self.id, victim, source_size - our_size);
+                return stolen;
             }
         }
         None
     }
 
+    /// Takes half of the victim's tasks: one to run right away, the rest
+    /// for our own queue, which saves coming back for each of them
+    fn steal_half_from(&self, victim: usize) -> Option<Task> {
+        let mut batch = self.queues[victim].steal_half().into_iter();
+        let first = batch.next()?;
+
+        let mut extra = 0;
+        for mut task in batch {
+            task.state = TaskState::Stolen;
+            self.local_queue.push(task);
+            extra += 1;
+        }
+        if extra > 0 {
+            println!("Worker {} took {} more tasks from worker {} for later", self.id, extra, victim);
+            self.tasks_stolen.fetch_add(extra, Ordering::SeqCst);
+        }
+        Some(first)
+    }
+
     fn all_work_complete(&self) -> bool {
         let completed = self.tasks_completed.load(Ordering::SeqCst);
-        let stolen = self.tasks_stolen.load(Ordering::SeqCst);
         let total = self.total_system_tasks.load(Ordering::SeqCst);
         
-        // Only exit if all tasks are accounted for AND there's no work in any queue
-        completed + stolen >= total && !self.work_exists_in_system()
+        // Stolen tasks count as completed too once they ran; a task that is
+        // still queued or running somewhere keeps everyone around, since
+        // nobody knows yet whether it will be stolen
+        completed >= total
     }
 
-    fn execute_task(&self, task: &mut Task) {
+    fn execute_task(&self, mut task: Task) {
         let status = if task.state == TaskState::Stolen { 
             "\x1b[32m(stolen)\x1b[0m" 
         } else { 
             "\x1b[34m(local)\x1b[0m" 
         };
         
-        println!("Worker {} executing task {} {} - work units: {} - Queue size: {}", 
+        task.state = TaskState::Running;
+        println!("Worker {} executing task {} {} - priority: {} - Queue size: {}", 
             self.id, 
             task.id,
             status,
-            task.work_units,
+            task.priority,
             self.local_queue.size()
         );
 
-        thread::sleep(Duration::from_millis(task.work_units as u64));
+        // Panics are caught inside the job and end up in its TaskHandle
+        (task.job)();
         
         task.state = TaskState::Completed;
         self.tasks_completed.fetch_add(1, Ordering::SeqCst);
     }
-
-    fn work_exists_in_system(&self) -> bool {
-        self.other_queues.iter().any(|q| q.size() > 0)
-    }
 }
 
 /// Work stealing scheduler that manages all workers
+/// Tasks are seeded with add_task() and executed by run(); the scheduler
+/// can be reused for further batches after that.
 struct WorkStealingScheduler {
     workers: Vec<Worker>,
     tasks_completed: Arc<AtomicUsize>,
@@ -225,42 +196,92 @@ struct WorkStealingScheduler {
     tasks_stolen: Arc<AtomicUsize>,
 }
 
-impl WorkStealingScheduler {
-    fn new(num_workers: usize) -> Self {
+/// Configures a WorkStealingScheduler before it is created
+/// Start from WorkStealingScheduler::builder() and finish with build()
+struct SchedulerBuilder {
+    num_workers: usize,
+    policy: Option<Arc<dyn StealPolicy>>,
+    min_imbalance: usize,
+    steal_half: bool,
+    producers: Vec<usize>,
+}
+
+impl SchedulerBuilder {
+    /// Decides which queues a thief tries, in which order (RandomVictim
+    /// by default)
+    fn steal_policy(mut self, policy: impl StealPolicy + 'static) -> Self {
+        self.policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Only steal from queues holding more than `tasks` tasks more than
+    /// the thief's own (0 by default: any queue that has more)
+    /// Higher values leave small imbalances alone, which saves steals
+    /// that would hardly pay off.
+    fn min_imbalance(mut self, tasks: usize) -> Self {
+        self.min_imbalance = tasks;
+        self
+    }
+
+    /// Steal half of the victim's queue at once instead of a single task
+    /// (off by default)
+    fn steal_half(mut self, enabled: bool) -> Self {
+        self.steal_half = enabled;
+        self
+    }
+
+    /// Makes `worker_id` a producer-only worker: it runs the tasks on its
+    /// own queue (and whatever they spawn) but never steals
+    /// Every worker is a full peer by default. A producer that stays out of
+    /// the others' queues keeps its own cache for generating work and never
+    /// competes with the workers it feeds; the price is that it idles once
+    /// its own queue runs dry.
+    fn producer_only(mut self, worker_id: usize) -> Self {
+        self.producers.push(worker_id);
+        self
+    }
+
+    /// Panics if a producer-only worker doesn't exist.
+    fn build(self) -> WorkStealingScheduler {
+        let num_workers = self.num_workers;
+        if let Some(&worker_id) = self.producers.iter().find(|&&id| id >= num_workers) {
+            panic!("producer-only worker {} doesn't exist, there are {} workers", worker_id, num_workers);
+        }
+        let steal = StealConfig {
+            policy: self.policy.unwrap_or_else(|| Arc::new(RandomVictim)),
+            min_imbalance: self.min_imbalance,
+            steal_half: self.steal_half,
+        };
         let tasks_completed = Arc::new(AtomicUsize::new(0));
         let tasks_stolen = Arc::new(AtomicUsize::new(0));
         let total_tasks = Arc::new(AtomicUsize::new(0));
+        let next_task_id = Arc::new(AtomicUsize::new(0));
         
-        // Create all queues that will be shared between workers
-        let queues: Vec<Arc<WorkStealingDeque>> = (0..num_workers)
-            .map(|_| Arc::new(WorkStealingDeque::new()))
+        // Create one queue per worker; the others get to steal from it
+        let queues: Vec<WorkStealingDeque<Task>> = (0..num_workers)
+            .map(|_| WorkStealingDeque::new())
             .collect();
+        let stealers: Vec<Stealer<Task>> = queues.iter().map(|queue| queue.stealer()).collect();
 
         println!("Created {} shared queues", queues.len());
         
         // Create workers
-        let workers = (0..num_workers)
-            .map(|worker_id| {
-                // Each worker's local queue is a reference to their corresponding shared queue
-                let local_queue = Arc::clone(&queues[worker_id]);
-                
-                // Other queues are all queues except their own
-                let other_queues: Vec<Arc<WorkStealingDeque>> = queues
-                    .iter()
-                    .enumerate()
-                    .filter(|&(idx, _)| idx != worker_id)
-                    .map(|(_, queue)| Arc::clone(queue))
-                    .collect();
-
-                println!("Worker {} sees {} other queues", worker_id, other_queues.len());
+        let workers = queues
+            .into_iter()
+            .enumerate()
+            .map(|(worker_id, local_queue)| {
+                println!("Worker {} sees {} other queues", worker_id, stealers.len() - 1);
                 
                 Worker {
                     id: worker_id,
-                    local_queue,  // This is now a reference to the shared queue
-                    other_queues,
+                    local_queue,
+                    queues: stealers.clone(),
+                    steal: steal.clone(),
+                    producer_only: self.producers.contains(&worker_id),
                     tasks_completed: Arc::clone(&tasks_completed),
                     tasks_stolen: Arc::clone(&tasks_stolen),
                     total_system_tasks: Arc::clone(&total_tasks),
+                    next_task_id: Arc::clone(&next_task_id),
                 }
             })
             .collect();
@@ -272,27 +293,61 @@ impl WorkStealingScheduler {
             tasks_stolen,
         }
     }
+}
+
+impl WorkStealingScheduler {
+    fn new(num_workers: usize) -> Self {
+        Self::builder(num_workers).build()
+    }
+
+    fn builder(num_workers: usize) -> SchedulerBuilder {
+        SchedulerBuilder {
+            num_workers,
+            policy: None,
+            min_imbalance: 0,
+            steal_half: false,
+            producers: Vec::new(),
+        }
+    }
+
+    /// Seeds a worker's queue with `func`; its result goes to the returned
+    /// handle once run() got to it
+    /// Only between runs: while running, the queues belong to the workers.
+    fn add_task<F, T>(&mut self, worker_id: usize, priority: usize, func: F) -> TaskHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let worker = &self.workers[worker_id];
+        let (task, handle) = Task::new(worker.next_task_id(), priority, func);
 
-    fn add_task(&mut self, worker_id: usize, task: Task) {
         println!("Adding task {} to worker {}'s queue", task.id, worker_id);
-        self.total_tasks.fetch_add(1, Ordering::SeqCst);
-        self.workers[worker_id].local_queue.push(task);
+        worker.push_task(task);
+        handle
     }
 
+    /// Runs every task added so far, one thread per worker, and returns
+    /// once all of them are done
     fn run(&mut self) {
-        let mut threads = Vec::new();
-        for worker in &self.workers {
-            let worker = worker.clone();
-            threads.push(thread::spawn(move || {
-                worker.run();
-            }));
-        }
+        let threads: Vec<_> = self
+            .workers
+            .drain(..)
+            .map(|worker| {
+                thread::spawn(move || {
+                    worker.run();
+                    worker
+                })
+            })
+            .collect();
 
-        for thread in threads {
-            thread.join().unwrap();
-        }
+        // The workers come back with their queues for the next batch
+        self.workers = threads.into_iter().map(|thread| thread.join().unwrap()).collect();
 
         println!("\n\x1b[35mAll workers have completed their work\x1b[0m");
+        println!("Tasks completed: {} of {}, stolen: {}",
+            self.tasks_completed.load(Ordering::SeqCst),
+            self.total_tasks.load(Ordering::SeqCst),
+            self.tasks_stolen.load(Ordering::SeqCst));
     }
 }
 
@@ -302,26 +357,27 @@ pub fn demo_work_stealing() {
     let mut scheduler = WorkStealingScheduler::new(4);
 
     // Create extremely unbalanced workload
+    let mut handles = Vec::new();
     for i in 0..12 {  // 12 total tasks
-        let task = Task {
-            id: i,
-            priority: i % 3,
-            work_units: if i < 9 {  // Worker 0 gets 9 long tasks
-                3000  // Worker 0's tasks take 3 seconds each
-            } else {
-                100   // Other workers get quick tasks
-            },
-            state: TaskState::Ready,
+        let work_units = if i < 9 {  // Worker 0 gets 9 long tasks
+            3000  // Worker 0's tasks take 3 seconds each
+        } else {
+            100   // Other workers get quick tasks
+        };
+        // The work is simulated by sleeping
+        let task = move || {
+            thread::sleep(Duration::from_millis(work_units));
+            work_units
         };
 
         // Give 9 out of 12 tasks to worker 0
         if i < 9 {
             println!("\x1b[34mAdding long task {} (3s) to worker 0\x1b[0m", i);
-            scheduler.add_task(0, task);
+            handles.push(scheduler.add_task(0, i % 3, task));
         } else {
             let worker_id = (i % 3) + 1;
             println!("\x1b[36mAdding quick task {} (0.2s) to worker {}\x1b[0m", i, worker_id);
-            scheduler.add_task(worker_id, task);
+            handles.push(scheduler.add_task(worker_id, i % 3, task));
         }
     }
 
@@ -332,9 +388,272 @@ pub fn demo_work_stealing() {
     println!("\n\x1b[35mStarting execution...\x1b[0m\n");
 
     scheduler.run();
+
+    let total: u64 = handles.into_iter().map(|handle| handle.join().unwrap()).sum();
+    assert_eq!(total, 9 * 3000 + 3 * 100);
+}
+
+/// The scheduler as a plain thread pool: tasks compute something and hand
+/// it back through their handles, whatever its type
+pub fn demo_thread_pool() {
+    println!("\n=== Thread Pool Demonstration ===\n");
+
+    let mut scheduler = WorkStealingScheduler::new(4);
+
+    // A sum split into chunks, all seeded on worker 0 for the others to steal
+    const CHUNKS: u64 = 16;
+    const N: u64 = 1_000_000;
+    let sums: Vec<TaskHandle<u64>> = (0..CHUNKS)
+        .map(|chunk| {
+            let range = chunk * N / CHUNKS..(chunk + 1) * N / CHUNKS;
+            scheduler.add_task(0, 1, move || range.map(|x| x * x).sum())
+        })
+        .collect();
+    // Results of any type, and a task that panics
+    let words = scheduler.add_task(1, 0, || "work stealing".split(' ').map(String::from).collect::<Vec<_>>());
+    let failing = scheduler.add_task(2, 0, || -> u64 { panic!("task failed on purpose") });
+
+    scheduler.run();
+    assert!(sums.iter().all(TaskHandle::is_finished));
+
+    let total: u64 = sums.into_iter().map(|handle| handle.join().unwrap()).sum();
+    assert_eq!(total, (N - 1) * N * (2 * N - 1) / 6);
+    println!("Sum of squares below {}: {}", N, total);
+    assert_eq!(words.join().unwrap(), ["work", "stealing"]);
+    let failing_id = failing.id();
+    let payload = failing.join().unwrap_err();
+    assert_eq!(payload.downcast_ref::<&str>(), Some(&"task failed on purpose"));
+    println!("Task {} panicked, which only its handle got to see", failing_id);
+
+    // The same scheduler takes another batch
+    let answer = scheduler.add_task(3, 0, || 6 * 7);
+    scheduler.run();
+    assert_eq!(answer.join().unwrap(), 42);
+}
+
+/// Below this, splitting the work costs more than it saves
+const SEQUENTIAL_CUTOFF: u64 = 20;
+
+/// The fib_recursive from work-scheduling, on our own join()
+fn fib(n: u64) -> u64 {
+    if n < SEQUENTIAL_CUTOFF {
+        return fib_sequential(n);
+    }
+    let (fib_n_minus_1, fib_n_minus_2) = join(|| fib(n - 1), || fib(n - 2));
+    fib_n_minus_1 + fib_n_minus_2
+}
+
+fn fib_sequential(n: u64) -> u64 {
+    if n < 2 {
+        return n;
+    }
+    fib_sequential(n - 1) + fib_sequential(n - 2)
+}
+
+/// Sorts in place; the halves are borrowed by the two sides of join()
+fn quicksort(items: &mut [u64]) {
+    if items.len() <= 1024 {
+        items.sort_unstable();
+        return;
+    }
+    let pivot = partition(items);
+    let (left, right) = items.split_at_mut(pivot);
+    join(|| quicksort(left), || quicksort(&mut right[1..]));
+}
+
+/// Lomuto partition around the last element; returns where it ended up
+fn partition(items: &mut [u64]) -> usize {
+    let last = items.len() - 1;
+    items.swap(last / 2, last);  // Middle element as pivot, for sorted input
+    let mut store = 0;
+    for i in 0..last {
+        if items[i] < items[last] {
+            items.swap(i, store);
+            store += 1;
+        }
+    }
+    items.swap(store, last);
+    store
+}
+
+/// Tasks that create more tasks while they run
+/// All of it starts out as a single task on worker 0; everything the other
+/// workers get to do, they steal.
+pub fn demo_fork_join() {
+    println!("\n=== Fork/Join Demonstration ===\n");
+
+    let mut scheduler = WorkStealingScheduler::new(4);
+
+    const N: u64 = 32;
+    let fib_handle = scheduler.add_task(0, 0, || fib(N));
+
+    // Pseudo-random input from a linear congruential generator
+    let mut seed = 42u64;
+    let input: Vec<u64> = (0..200_000)
+        .map(|_| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            seed >> 33
+        })
+        .collect();
+    let sort_handle = scheduler.add_task(0, 0, move || {
+        let mut items = input;
+        quicksort(&mut items);
+        items
+    });
+
+    // spawn() hands back handles; they travel out of the task to be joined
+    let spawner = scheduler.add_task(0, 0, || {
+        (1..=8u64)
+            .map(|i| spawn(move || (1..=i).product::<u64>()))
+            .collect::<Vec<_>>()
+    });
+
+    scheduler.run();
+
+    let fib_result = fib_handle.join().unwrap();
+    assert_eq!(fib_result, fib_sequential(N));
+    println!("fib({}) = {}", N, fib_result);
+
+    let sorted = sort_handle.join().unwrap();
+    assert!(sorted.windows(2).all(|pair| pair[0] <= pair[1]));
+    println!("Sorted {} numbers", sorted.len());
+
+    let factorials: Vec<u64> = spawner
+        .join()
+        .unwrap()
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect();
+    assert_eq!(factorials, [1, 2, 6, 24, 120, 720, 5040, 40320]);
+    println!("Spawned factorials: {:?}", factorials);
+
+    // Outside of a worker, join() simply runs both sides here
+    assert_eq!(join(|| fib(SEQUENTIAL_CUTOFF + 1), || 1), (fib_sequential(SEQUENTIAL_CUTOFF + 1), 1));
+    assert_eq!(context::current_worker_id(), None);
+}
+
+/// The same unbalanced workload under every steal policy
+/// Worker 0 starts with most of the tasks and worker 2 with the rest;
+/// LocalityAware puts workers 0-1 and 2-3 on the same node.
+pub fn demo_steal_policies() {
+    println!("\n=== Steal Policy Demonstration ===\n");
+
+    let configs = [
+        ("random victim", WorkStealingScheduler::builder(4)),
+        ("round robin", WorkStealingScheduler::builder(4).steal_policy(RoundRobin::default())),
+        ("largest queue first", WorkStealingScheduler::builder(4).steal_policy(LargestQueueFirst)),
+        ("locality aware", WorkStealingScheduler::builder(4).steal_policy(LocalityAware::new(2))),
+        (
+            "largest first, steal half",
+            WorkStealingScheduler::builder(4).steal_policy(LargestQueueFirst).steal_half(true),
+        ),
+        ("random, min imbalance 4", WorkStealingScheduler::builder(4).min_imbalance(4)),
+        ("random, worker 0 producer", WorkStealingScheduler::builder(4).producer_only(0)),
+    ];
+
+    let mut results = Vec::new();
+    for (name, builder) in configs {
+        println!("\n\x1b[35m--- {} ---\x1b[0m", name);
+        let mut scheduler = builder.build();
+        let handles: Vec<TaskHandle<u64>> = (0..24)
+            .map(|i| scheduler.add_task(if i < 16 { 0 } else { 2 }, 0, || fib_sequential(27)))
+            .collect();
+
+        let start = Instant::now();
+        scheduler.run();
+        let elapsed = start.elapsed();
+
+        assert!(handles.into_iter().all(|handle| handle.join().unwrap() == fib_sequential(27)));
+        results.push((name, elapsed, scheduler.tasks_stolen.load(Ordering::SeqCst)));
+    }
+
+    println!("\n{:<28} {:>10} {:>8}", "Policy", "Time", "Stolen");
+    for (name, elapsed, stolen) in results {
+        println!("{:<28} {:>8}ms {:>8}", name, elapsed.as_millis(), stolen);
+    }
 }
 
 // Example usage in main:
 fn main() {
     demo_work_stealing();
-}
\ No newline at end of file
+    demo_thread_pool();
+    demo_fork_join();
+    demo_steal_policies();
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    /// Runs `tasks` slow tasks seeded on `seed_worker`; returns the ids of
+    /// the workers that ran them
+    fn workers_used(scheduler: &mut WorkStealingScheduler, seed_worker: usize, tasks: usize) -> HashSet<usize> {
+        let handles: Vec<_> = (0..tasks)
+            .map(|_| {
+                scheduler.add_task(seed_worker, 0, || {
+                    thread::sleep(Duration::from_millis(20));
+                    context::current_worker_id().unwrap()
+                })
+            })
+            .collect();
+        scheduler.run();
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    }
+
+    #[test]
+    fn every_worker_steals_including_worker_0() {
+        let mut scheduler = WorkStealingScheduler::new(3);
+        assert_eq!(workers_used(&mut scheduler, 2, 30), HashSet::from([0, 1, 2]));
+    }
+
+    #[test]
+    fn producer_only_workers_never_steal() {
+        let mut scheduler = WorkStealingScheduler::builder(3).producer_only(0).build();
+        assert_eq!(workers_used(&mut scheduler, 1, 30), HashSet::from([1, 2]));
+
+        // Their own tasks they do run
+        assert!(workers_used(&mut scheduler, 0, 30).contains(&0));
+    }
+
+    /// Seeds `tasks` empty tasks on worker 0 and returns their handles
+    fn seed(scheduler: &mut WorkStealingScheduler, tasks: usize) -> Vec<TaskHandle<()>> {
+        (0..tasks).map(|_| scheduler.add_task(0, 0, || ())).collect()
+    }
+
+    #[test]
+    fn a_small_imbalance_is_left_alone() {
+        let mut scheduler = WorkStealingScheduler::builder(2).min_imbalance(3).build();
+        let mut handles = seed(&mut scheduler, 3);
+
+        // Worker 1 has none, worker 0 has no more than 3 extra
+        assert!(scheduler.workers[1].steal_task().is_none());
+        assert_eq!(scheduler.workers[0].local_queue.size(), 3);
+
+        // One more, and it's worth it
+        handles.extend(seed(&mut scheduler, 1));
+        let task = scheduler.workers[1].steal_task().unwrap();
+        assert_eq!(task.id, handles[0].id());
+        scheduler.workers[1].execute_task(task);
+
+        scheduler.run();
+        assert!(handles.into_iter().all(|handle| handle.join().is_ok()));
+    }
+
+    #[test]
+    fn steal_half_keeps_the_extra_tasks_for_later() {
+        let mut scheduler = WorkStealingScheduler::builder(2).steal_half(true).build();
+        let handles = seed(&mut scheduler, 8);
+
+        // Half of 8: the oldest to run now, three more queued on the thief
+        let task = scheduler.workers[1].steal_task().unwrap();
+        assert_eq!(task.id, handles[0].id());
+        assert_eq!(scheduler.workers[0].local_queue.size(), 4);
+        assert_eq!(scheduler.workers[1].local_queue.size(), 3);
+        assert_eq!(scheduler.tasks_stolen.load(Ordering::SeqCst), 3);
+        scheduler.workers[1].execute_task(task);
+
+        scheduler.run();
+        assert!(handles.into_iter().all(|handle| handle.join().is_ok()));
+    }
+}
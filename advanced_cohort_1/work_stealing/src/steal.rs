@@ -0,0 +1,154 @@
+//! Steal policies
+//! When a worker runs dry it has to pick a victim. The deque makes each
+//! steal cheap; the policy decides which queues are worth trying, and in
+//! what order:
+//! - RandomVictim: a random order every time (the default); no two thieves
+//!   keep piling onto the same queue
+//! - RoundRobin: everybody in turn, continuing where the last steal left off
+//! - LargestQueueFirst: the longest queues first; the best odds of finding
+//!   work, at the price of every thief converging on the same victim
+//! - LocalityAware: workers on the thief's own NUMA node first, largest
+//!   queue first, remote ones only once the local node has nothing left
+//!
+//! How much imbalance makes a steal worthwhile, and whether a steal takes
+//! one task or half of the victim's queue, are scheduler settings that
+//! apply whatever the policy (see SchedulerBuilder).
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rand::seq::SliceRandom;
+
+/// Picks the queues a thief should try to steal from
+/// Implement this to try out a policy of your own.
+pub trait StealPolicy: Send + Sync {
+    /// The workers `thief` should try, best first
+    /// `queue_sizes` holds the length of every worker's queue, the thief's
+    /// own included, indexed by worker id. Workers left out aren't tried.
+    fn victims(&self, thief: usize, queue_sizes: &[usize]) -> Vec<usize>;
+}
+
+/// Every worker but the thief, in id order
+fn others(thief: usize, workers: usize) -> impl Iterator<Item = usize> {
+    (0..workers).filter(move |&worker| worker != thief)
+}
+
+/// Tries the other workers in a random order
+#[derive(Debug, Default)]
+pub struct RandomVictim;
+
+impl StealPolicy for RandomVictim {
+    fn victims(&self, thief: usize, queue_sizes: &[usize]) -> Vec<usize> {
+        let mut victims: Vec<usize> = others(thief, queue_sizes.len()).collect();
+        victims.shuffle(&mut rand::thread_rng());
+        victims
+    }
+}
+
+/// Tries the other workers in turn
+/// Each steal attempt starts one worker further than the previous one
+/// did, so that the thieves spread out over the queues.
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl StealPolicy for RoundRobin {
+    fn victims(&self, thief: usize, queue_sizes: &[usize]) -> Vec<usize> {
+        let workers = queue_sizes.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..workers)
+            .map(|offset| (start + offset) % workers)
+            .filter(|&worker| worker != thief)
+            .collect()
+    }
+}
+
+/// Tries the longest queues first
+#[derive(Debug, Default)]
+pub struct LargestQueueFirst;
+
+impl StealPolicy for LargestQueueFirst {
+    fn victims(&self, thief: usize, queue_sizes: &[usize]) -> Vec<usize> {
+        let mut victims: Vec<usize> = others(thief, queue_sizes.len()).collect();
+        // Stable, so equal queues keep their id order
+        victims.sort_by_key(|&worker| std::cmp::Reverse(queue_sizes[worker]));
+        victims
+    }
+}
+
+/// Prefers victims on the thief's own NUMA node
+/// Their tasks' data is more likely to sit in memory close by, and maybe
+/// even in a shared cache. Workers are grouped into nodes by id: workers
+/// 0..workers_per_node-1 make up node 0 and so on. Matching that to the
+/// machine (the cpulists under /sys/devices/system/node/) and pinning the
+/// threads accordingly is up to whoever creates the scheduler.
+#[derive(Debug)]
+pub struct LocalityAware {
+    workers_per_node: usize,
+}
+
+impl LocalityAware {
+    /// Panics if `workers_per_node` is 0.
+    pub fn new(workers_per_node: usize) -> Self {
+        assert!(workers_per_node > 0, "a NUMA node needs at least one worker");
+        LocalityAware { workers_per_node }
+    }
+
+    fn node(&self, worker: usize) -> usize {
+        worker / self.workers_per_node
+    }
+}
+
+impl StealPolicy for LocalityAware {
+    fn victims(&self, thief: usize, queue_sizes: &[usize]) -> Vec<usize> {
+        let home = self.node(thief);
+        let mut victims: Vec<usize> = others(thief, queue_sizes.len()).collect();
+        // Local before remote, then the longest queue first
+        victims.sort_by_key(|&worker| (self.node(worker) != home, std::cmp::Reverse(queue_sizes[worker])));
+        victims
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZES: [usize; 6] = [5, 0, 2, 9, 1, 7];
+
+    #[test]
+    fn every_policy_offers_each_other_worker_once() {
+        let policies: [Box<dyn StealPolicy>; 4] = [
+            Box::new(RandomVictim),
+            Box::new(RoundRobin::default()),
+            Box::new(LargestQueueFirst),
+            Box::new(LocalityAware::new(2)),
+        ];
+        for policy in &policies {
+            let mut victims = policy.victims(1, &SIZES);
+            victims.sort_unstable();
+            assert_eq!(victims, [0, 2, 3, 4, 5]);
+        }
+    }
+
+    #[test]
+    fn round_robin_moves_on_every_time() {
+        let policy = RoundRobin::default();
+        assert_eq!(policy.victims(0, &SIZES[..4]), [1, 2, 3]);
+        assert_eq!(policy.victims(0, &SIZES[..4]), [1, 2, 3]);
+        assert_eq!(policy.victims(0, &SIZES[..4]), [2, 3, 1]);
+        assert_eq!(policy.victims(0, &SIZES[..4]), [3, 1, 2]);
+    }
+
+    #[test]
+    fn largest_queue_first_sorts_by_size() {
+        assert_eq!(LargestQueueFirst.victims(0, &SIZES), [3, 5, 2, 4, 1]);
+    }
+
+    #[test]
+    fn locality_aware_stays_on_the_node_first() {
+        // Nodes: {0, 1, 2}, {3, 4, 5}
+        let policy = LocalityAware::new(3);
+        assert_eq!(policy.victims(1, &SIZES), [0, 2, 3, 5, 4]);
+        assert_eq!(policy.victims(4, &SIZES), [3, 5, 0, 2, 1]);
+    }
+}
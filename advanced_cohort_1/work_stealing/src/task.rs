@@ -0,0 +1,158 @@
+//! Tasks and their result handles
+//! A task is any `FnOnce() -> T + Send` closure. The scheduler only sees it
+//! as a boxed job that returns nothing; the job itself stores the result
+//! in a slot shared with the TaskHandle that add_task() handed out:
+//! 1. The closure's return value (or its panic) goes into the slot
+//! 2. Whoever holds the handle blocks on the slot's Condvar until then
+//!
+//! A panicking task thus doesn't take its worker down, it just hands the
+//! panic to join(), like std::thread::JoinHandle does.
+
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+/// A job erased to something every worker can run
+pub type Job = Box<dyn FnOnce() + Send>;
+
+/// Represents a task that can be executed by our workers
+pub struct Task {
+    pub id: usize,
+    pub priority: usize,
+    pub job: Job,
+    pub state: TaskState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TaskState {
+    Ready,
+    Running,
+    Completed,
+    Stolen,
+}
+
+/// Where a task leaves its result for the handle
+struct ResultSlot<T> {
+    result: Mutex<Option<thread::Result<T>>>,
+    ready: Condvar,
+}
+
+/// Gives access to the result of a task
+pub struct TaskHandle<T> {
+    id: usize,
+    slot: Arc<ResultSlot<T>>,
+}
+
+impl Task {
+    /// Wraps `func` into a task plus the handle its result goes to
+    pub fn new<F, T>(id: usize, priority: usize, func: F) -> (Task, TaskHandle<T>)
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        // SAFETY: nothing borrowed, nothing that could go away
+        unsafe { Self::new_unchecked(id, priority, func) }
+    }
+
+    /// Same as new(), for closures that borrow from their creator
+    ///
+    /// # Safety
+    /// The task must have run before anything `func` borrows goes away;
+    /// join() makes sure of that by waiting for it.
+    pub unsafe fn new_unchecked<'a, F, T>(id: usize, priority: usize, func: F) -> (Task, TaskHandle<T>)
+    where
+        F: FnOnce() -> T + Send + 'a,
+        T: Send + 'a,
+    {
+        let slot = Arc::new(ResultSlot { result: Mutex::new(None), ready: Condvar::new() });
+
+        let job_slot = slot.clone();
+        let job: Box<dyn FnOnce() + Send + 'a> = Box::new(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(func));
+            *job_slot.result.lock().unwrap() = Some(result);
+            job_slot.ready.notify_all();
+        });
+        // Erase the lifetime; the caller vouched for it
+        let job: Job = mem::transmute::<Box<dyn FnOnce() + Send + 'a>, Job>(job);
+
+        let task = Task { id, priority, job, state: TaskState::Ready };
+        (task, TaskHandle { id, slot })
+    }
+}
+
+impl<T> TaskHandle<T> {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Whether the task has run, so join() won't block
+    pub fn is_finished(&self) -> bool {
+        self.slot.result.lock().unwrap().is_some()
+    }
+
+    /// Waits for the task and returns its result, or the payload it
+    /// panicked with
+    /// Blocks the calling thread until a worker has run the task, so only
+    /// call it after run() or from a thread other than the one calling it.
+    pub fn join(self) -> thread::Result<T> {
+        let mut result = self.slot.result.lock().unwrap();
+        loop {
+            match result.take() {
+                Some(result) => return result,
+                None => result = self.slot.ready.wait(result).unwrap(),
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn join_returns_the_result_of_any_type() {
+        let (task, handle) = Task::new(3, 0, || "work stealing".split(' ').map(String::from).collect::<Vec<_>>());
+        assert_eq!((task.id, handle.id()), (3, 3));
+        assert!(!handle.is_finished());
+
+        (task.job)();
+        assert!(handle.is_finished());
+        assert_eq!(handle.join().unwrap(), ["work", "stealing"]);
+    }
+
+    #[test]
+    fn a_panic_reaches_join_instead_of_the_worker() {
+        let (task, handle) = Task::new(0, 0, || -> u64 { panic!("task failed on purpose") });
+
+        // Whoever runs the job carries on as if nothing happened
+        (task.job)();
+        assert!(handle.is_finished());
+        let payload = handle.join().unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"task failed on purpose"));
+    }
+
+    #[test]
+    fn join_blocks_until_another_thread_ran_the_task() {
+        let (task, handle) = Task::new(0, 0, || 6 * 7);
+
+        let runner = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            (task.job)();
+        });
+        assert_eq!(handle.join().unwrap(), 42);
+        runner.join().unwrap();
+    }
+
+    #[test]
+    fn an_unchecked_task_may_borrow() {
+        let mut items = vec![3, 1, 2];
+
+        // SAFETY: the job runs right here, while `items` is still around
+        let (task, handle) = unsafe { Task::new_unchecked(0, 0, || items.sort()) };
+        (task.job)();
+        handle.join().unwrap();
+        assert_eq!(items, [1, 2, 3]);
+    }
+}